@@ -0,0 +1,72 @@
+use bson::oid::ObjectId;
+
+use crate::{
+    db::{Db, IntoObjectId},
+    models::{Chat, ErrorCode, FrameError, Group, GroupPermission},
+};
+
+// Access checks shared by the REST routes and the WebSocket, so both answer the same way.
+// A missing chat or group is a not found error, existing but not yours is forbidden.
+
+pub async fn chat_participant(
+    db: &Db,
+    user_id: &str,
+    chat_id: ObjectId,
+) -> Result<Chat, FrameError> {
+    let chat = match db.find_chat(chat_id).await {
+        Some(c) => c,
+        None => {
+            return Err(FrameError::new(
+                ErrorCode::ChatNotFound,
+                "chat does not exist",
+            ))
+        }
+    };
+    if chat.has_user(user_id.to_string().into_object_id()) {
+        Ok(chat)
+    } else {
+        Err(FrameError::new(
+            ErrorCode::Forbidden,
+            "you are not a participant of this chat",
+        ))
+    }
+}
+
+pub async fn group_member(db: &Db, user_id: &str, group_id: ObjectId) -> Result<Group, FrameError> {
+    let group = match db.find_group(group_id).await {
+        Some(g) => g,
+        None => {
+            return Err(FrameError::new(
+                ErrorCode::GroupNotFound,
+                "group does not exist",
+            ))
+        }
+    };
+    if group
+        .members
+        .contains(&user_id.to_string().into_object_id())
+    {
+        Ok(group)
+    } else {
+        Err(FrameError::new(
+            ErrorCode::Forbidden,
+            "you are not a member of this group",
+        ))
+    }
+}
+
+// Membership plus a role check, `denied` is the message for members whose role is too low
+pub async fn group_permission(
+    db: &Db,
+    user_id: &str,
+    group_id: ObjectId,
+    permission: GroupPermission,
+    denied: &str,
+) -> Result<Group, FrameError> {
+    let group = group_member(db, user_id, group_id).await?;
+    if group.can(user_id.to_string().into_object_id(), permission) {
+        Ok(group)
+    } else {
+        Err(FrameError::new(ErrorCode::Forbidden, denied))
+    }
+}
@@ -1,16 +1,17 @@
 use crate::models::*;
 use futures::StreamExt;
-use log::{debug, error, info};
-use mongodb::bson::Document;
-use mongodb::error::Error;
+use log::{debug, error, info, warn};
+use mongodb::bson::{to_bson, Document};
+use mongodb::error::{Error, ErrorKind, WriteFailure};
 use mongodb::results::InsertOneResult;
 use mongodb::{
     bson::{doc, oid::ObjectId, Bson, DateTime},
-    options::FindOptions,
-    Client, Collection, Cursor,
+    options::{FindOptions, IndexOptions, ReturnDocument},
+    Client, Collection, Cursor, IndexModel,
 };
-use std::collections::HashSet;
-use std::{env, str::FromStr, sync::Arc};
+use serde::de::DeserializeOwned;
+use std::collections::{HashMap, HashSet};
+use std::{env, str::FromStr, sync::Arc, time::Duration};
 pub trait IntoObjectId {
     fn into_object_id(self) -> ObjectId;
     fn to_string(self) -> String;
@@ -25,9 +26,92 @@ pub struct Db {
     groups: Arc<Collection<Group>>,
     requests: Arc<Collection<Requests>>,
     group_messages: Arc<Collection<GroupMessage>>,
+    sessions: Arc<Collection<Session>>,
+    group_invites: Arc<Collection<GroupInvite>>,
+    group_join_requests: Arc<Collection<GroupJoinRequest>>,
+    read_markers: Arc<Collection<ReadMarker>>,
+    changes: Arc<Collection<Change>>,
+    // named counters, `{_id: name, seq}`
+    counters: Arc<Collection<Document>>,
     // otp: Arc<Collection<OneTimePass>>,
 }
 
+fn is_duplicate_key(e: &Error) -> bool {
+    matches!(
+        e.kind.as_ref(),
+        ErrorKind::Write(WriteFailure::WriteError(w)) if w.code == 11000
+    )
+}
+
+// How to find what a sender already stored under `client_msg_id`. Sends without one are never retries.
+fn retry_filter(from_id: Option<ObjectId>, client_msg_id: &Option<String>) -> Option<Document> {
+    client_msg_id
+        .as_ref()
+        .map(|id| doc! {"from_id":from_id,"client_msg_id":id})
+}
+
+// Timeline messages after the watermark, by seq when it has one. Thread replies count their
+// seq within the thread, so those filters leave them out.
+fn after_watermark(mark: &Watermark) -> Document {
+    match mark.seq {
+        Some(seq) => doc! {"seq":{"$gt":seq},"thread_id":{"$exists":false}},
+        None => doc! {"_id":{"$gt":mark.message_id}},
+    }
+}
+
+// Timeline messages up to and including the watermark, see after_watermark
+fn up_to_watermark(mark: &Watermark) -> Document {
+    match mark.seq {
+        Some(seq) => doc! {"seq":{"$lte":seq},"thread_id":{"$exists":false}},
+        None => doc! {"_id":{"$lte":mark.message_id}},
+    }
+}
+
+// `$set` fields of an update pipeline that move `field` and `{field}_seq` to `to` when it is
+// further along, by the same rule as Watermark::is_before
+fn raise_watermark(field: &str, to: &Watermark) -> Document {
+    let id = format!("${}", field);
+    let seq = format!("${}_seq", field);
+    let forward = match to.seq {
+        Some(to_seq) => doc! {"$cond":[
+            {"$isNumber":seq.as_str()},
+            {"$gt":[to_seq, seq.as_str()]},
+            {"$gt":[to.message_id, id.as_str()]}
+        ]},
+        None => doc! {"$and":[
+            {"$not":[{"$isNumber":seq.as_str()}]},
+            {"$gt":[to.message_id, id.as_str()]}
+        ]},
+    };
+    let mut set = Document::new();
+    set.insert(field, doc! {"$cond":[forward.clone(), to.message_id, id.as_str()]});
+    if let Some(to_seq) = to.seq {
+        set.insert(
+            format!("{}_seq", field),
+            doc! {"$cond":[forward, to_seq, seq.as_str()]},
+        );
+    }
+    set
+}
+
+// Roles for a group stored with a plain admin list. The creator owns it, or the only admin when
+// no creator was stored; with several admins nobody can tell, so there is no owner.
+fn legacy_roles(admins: &[ObjectId], creator: Option<ObjectId>) -> (Document, Option<ObjectId>) {
+    let owner = match (creator, admins) {
+        (Some(creator), _) => Some(creator),
+        (None, [only]) => Some(*only),
+        (None, _) => None,
+    };
+    let mut roles = Document::new();
+    for admin in admins {
+        roles.insert(admin.to_hex(), "admin");
+    }
+    if let Some(owner) = owner {
+        roles.insert(owner.to_hex(), "owner");
+    }
+    (roles, owner)
+}
+
 impl IntoObjectId for String {
     fn into_object_id(self) -> ObjectId {
         ObjectId::from_str(&self).unwrap()
@@ -62,8 +146,15 @@ impl Db {
                 let groups = Arc::new(db.collection::<Group>("groups"));
                 let requests = Arc::new(db.collection::<Requests>("requests"));
                 let group_messages = Arc::new(db.collection::<GroupMessage>("group_messages"));
+                let sessions = Arc::new(db.collection::<Session>("sessions"));
+                let group_invites = Arc::new(db.collection::<GroupInvite>("group_invites"));
+                let group_join_requests =
+                    Arc::new(db.collection::<GroupJoinRequest>("group_join_requests"));
+                let read_markers = Arc::new(db.collection::<ReadMarker>("read_markers"));
+                let changes = Arc::new(db.collection::<Change>("changes"));
+                let counters = Arc::new(db.collection::<Document>("counters"));
                 // let otp = Arc::new(db.collection::<OneTimePass>("one_time_passwords"));
-                Ok(Db {
+                let store = Db {
                     users,
                     friends,
                     chats,
@@ -71,7 +162,20 @@ impl Db {
                     groups,
                     requests,
                     group_messages,
-                })
+                    sessions,
+                    group_invites,
+                    group_join_requests,
+                    read_markers,
+                    changes,
+                    counters,
+                };
+                if let Err(e) = store.ensure_indexes().await {
+                    error!("{}", e);
+                }
+                if let Err(e) = store.migrate_group_roles().await {
+                    error!("{}", e);
+                }
+                Ok(store)
             }
             Err(e) => {
                 error!("{}", e.to_string());
@@ -80,6 +184,224 @@ impl Db {
         }
     }
 
+    async fn ensure_indexes(&self) -> Result<(), MyError> {
+        // A sender can only use a client_msg_id once, messages without one are not affected
+        let client_msg_id = IndexModel::builder()
+            .keys(doc! {"from_id":1,"client_msg_id":1})
+            .options(
+                IndexOptions::builder()
+                    .name(String::from("from_id_client_msg_id"))
+                    .unique(true)
+                    .partial_filter_expression(doc! {"client_msg_id":{"$type":"string"}})
+                    .build(),
+            )
+            .build();
+        self.messages.create_index(client_msg_id.clone()).await?;
+        self.group_messages.create_index(client_msg_id).await?;
+        // History pages walk a chat by _id, or by created_at when the cursor is a time
+        let by_id = IndexModel::builder()
+            .keys(doc! {"chat_id":1,"_id":-1})
+            .build();
+        let by_time = IndexModel::builder()
+            .keys(doc! {"chat_id":1,"created_at":-1,"_id":-1})
+            .build();
+        self.messages.create_indexes([by_id, by_time]).await?;
+        let by_id = IndexModel::builder()
+            .keys(doc! {"group_id":1,"_id":-1})
+            .build();
+        let by_time = IndexModel::builder()
+            .keys(doc! {"group_id":1,"created_at":-1,"_id":-1})
+            .build();
+        self.group_messages.create_indexes([by_id, by_time]).await?;
+        let by_id = IndexModel::builder()
+            .keys(doc! {"thread_id":1,"_id":-1})
+            .options(IndexOptions::builder().sparse(true).build())
+            .build();
+        let by_time = IndexModel::builder()
+            .keys(doc! {"thread_id":1,"created_at":-1,"_id":-1})
+            .options(IndexOptions::builder().sparse(true).build())
+            .build();
+        self.group_messages.create_indexes([by_id, by_time]).await?;
+        // No two messages share a seq in one chat, group timeline or thread
+        let seq_options = IndexOptions::builder()
+            .unique(true)
+            .partial_filter_expression(doc! {"seq":{"$exists":true}})
+            .build();
+        let by_seq = IndexModel::builder()
+            .keys(doc! {"chat_id":1,"seq":-1})
+            .options(seq_options.clone())
+            .build();
+        self.messages.create_index(by_seq).await?;
+        let by_seq = IndexModel::builder()
+            .keys(doc! {"group_id":1,"thread_id":1,"seq":-1})
+            .options(seq_options)
+            .build();
+        self.group_messages.create_index(by_seq).await?;
+        let token = IndexModel::builder()
+            .keys(doc! {"token":1})
+            .options(
+                IndexOptions::builder()
+                    .name(String::from("invite_token"))
+                    .unique(true)
+                    .build(),
+            )
+            .build();
+        self.group_invites.create_index(token).await?;
+        // One pending request per user and group, handled ones are kept as history
+        let pending = IndexModel::builder()
+            .keys(doc! {"group_id":1,"from_id":1})
+            .options(
+                IndexOptions::builder()
+                    .name(String::from("pending_join_request"))
+                    .unique(true)
+                    .partial_filter_expression(doc! {"status":"pending"})
+                    .build(),
+            )
+            .build();
+        self.group_join_requests.create_index(pending).await?;
+        // One marker per user and conversation, the other id is missing and indexed as null
+        let marker = IndexModel::builder()
+            .keys(doc! {"user_id":1,"chat_id":1,"group_id":1})
+            .options(
+                IndexOptions::builder()
+                    .name(String::from("read_marker"))
+                    .unique(true)
+                    .build(),
+            )
+            .build();
+        self.read_markers.create_index(marker).await?;
+        // Syncs read the log in seq order, for every conversation or for one,
+        // and find where a conversation cursor is by the message it names
+        let seq = IndexModel::builder()
+            .keys(doc! {"seq":1})
+            .options(IndexOptions::builder().unique(true).build())
+            .build();
+        let chat_seq = IndexModel::builder()
+            .keys(doc! {"chat_id":1,"seq":1})
+            .options(IndexOptions::builder().sparse(true).build())
+            .build();
+        let group_seq = IndexModel::builder()
+            .keys(doc! {"group_id":1,"seq":1})
+            .options(IndexOptions::builder().sparse(true).build())
+            .build();
+        let message = IndexModel::builder()
+            .keys(doc! {"event.message._id":1})
+            .options(IndexOptions::builder().sparse(true).build())
+            .build();
+        let ttl = IndexModel::builder()
+            .keys(doc! {"created_at":1})
+            .options(
+                IndexOptions::builder()
+                    .expire_after(Duration::from_secs(CHANGE_LOG_TTL_SECS))
+                    .build(),
+            )
+            .build();
+        self.changes
+            .create_indexes([seq, chat_seq, group_seq, message, ttl])
+            .await?;
+        Ok(())
+    }
+
+    // Groups created before roles only have an `admins` list. It was a set, so its order says
+    // nothing about who created the group: the owner is `created_by` when the document has one,
+    // or the only admin. Anything else is left without an owner for someone to sort out by hand.
+    async fn migrate_group_roles(&self) -> Result<(), MyError> {
+        let groups = self.groups.clone_with_type::<Document>();
+        let mut cursor = groups.find(doc! {"admins":{"$exists":true}}).await?;
+        while let Some(group) = cursor.next().await {
+            let group = group?;
+            let id = group.get_object_id("_id").map_err(|e| MyError::from_error(e, "db : migrate group roles"))?;
+            let admins: Vec<ObjectId> = group
+                .get_array("admins")
+                .into_iter()
+                .flatten()
+                .filter_map(Bson::as_object_id)
+                .collect();
+            let (roles, owner) = legacy_roles(&admins, group.get_object_id("created_by").ok());
+            if owner.is_none() {
+                warn!("group {} has {} admins and no creator, migrated without an owner", id, admins.len());
+            }
+            groups
+                .update_one(
+                    doc! {"_id":id},
+                    doc! {"$set":{"roles":roles},"$unset":{"admins":""}},
+                )
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// Reads one newest-first page of `coll` matching `filter`.
+    /// With only `after` the page is the oldest items past the cursor, so a client catching up
+    /// keeps passing `next_cursor` as `after`; otherwise it walks back in time with `before`.
+    /// Paging by seq goes on with `next_seq`, every other kind of cursor with an `_id` in `next_cursor`.
+    async fn paginate<T>(
+        &self,
+        coll: &Collection<T>,
+        mut filter: Document,
+        page: &PageRequest,
+    ) -> Result<Page<T>, MyError>
+    where
+        T: DeserializeOwned + Identified + Send + Sync,
+    {
+        let by_seq = matches!(page.before, Some(PageCursor::Seq(_)))
+            || matches!(page.after, Some(PageCursor::Seq(_)));
+        let by_time = matches!(page.before, Some(PageCursor::Time(_)))
+            || matches!(page.after, Some(PageCursor::Time(_)));
+        for (cursor, op) in [(page.before, "$lt"), (page.after, "$gt")] {
+            match cursor {
+                Some(PageCursor::Id(id)) => {
+                    filter.insert("_id", doc! {op: id});
+                }
+                Some(PageCursor::Time(t)) => {
+                    filter.insert("created_at", doc! {op: t});
+                }
+                Some(PageCursor::Seq(seq)) => {
+                    filter.insert("seq", doc! {op: seq});
+                }
+                None => (),
+            }
+        }
+        let forward = page.after.is_some() && page.before.is_none();
+        let order = if forward { 1 } else { -1 };
+        let sort = if by_seq {
+            doc! {"seq":order}
+        } else if by_time {
+            doc! {"created_at":order,"_id":order}
+        } else {
+            doc! {"_id":order}
+        };
+        let res = coll.find(filter).sort(sort).limit(page.limit + 1).await;
+        match res {
+            Ok(mut c) => {
+                let mut items = vec![];
+                while let Some(item) = c.next().await {
+                    match item {
+                        Ok(i) => items.push(i),
+                        Err(e) => error!("{}", e),
+                    }
+                }
+                let has_more = items.len() as i64 > page.limit;
+                items.truncate(page.limit as usize);
+                let last = items.last().filter(|_| has_more);
+                let (next_cursor, next_seq) = match last {
+                    Some(last) if by_seq => (None, last.seq()),
+                    Some(last) => (last.object_id(), None),
+                    None => (None, None),
+                };
+                if forward {
+                    items.reverse();
+                }
+                Ok(Page {
+                    items,
+                    next_cursor,
+                    next_seq,
+                })
+            }
+            Err(e) => Err(MyError::from_error(e, "db : paginate")),
+        }
+    }
+
     pub async fn find_user_with_id(&self, id: impl IntoObjectId) -> Option<User> {
         let res = self.users.find_one(doc! {"_id":id.into_object_id()}).await;
         match res {
@@ -202,6 +524,44 @@ impl Db {
         }
     }
 
+    pub async fn set_last_seen(&self, user: ObjectId, at: DateTime) -> Result<(), MyError> {
+        match self
+            .users
+            .update_one(doc! {"_id":user}, doc! {"$set":{"last_seen":at}})
+            .await
+        {
+            Ok(_) => Ok(()),
+            Err(e) => Err(MyError::from_error(e, "db : set last seen")),
+        }
+    }
+
+    // Users without a last_seen yet are left out
+    pub async fn get_last_seen(
+        &self,
+        users: &[ObjectId],
+    ) -> Result<HashMap<ObjectId, DateTime>, MyError> {
+        let mut cursor = self.users.find(doc! {"_id":{"$in":users}}).await?;
+        let mut seen = HashMap::new();
+        while let Some(user) = cursor.next().await {
+            let user = user?;
+            if let (Some(id), Some(last_seen)) = (user.id, user.last_seen) {
+                seen.insert(id, last_seen);
+            }
+        }
+        Ok(seen)
+    }
+
+    pub async fn get_friend_ids(&self, user: ObjectId) -> Result<Vec<ObjectId>, MyError> {
+        match self.friends.distinct("users", doc! {"users":user}).await {
+            Ok(ids) => Ok(ids
+                .iter()
+                .filter_map(Bson::as_object_id)
+                .filter(|id| *id != user)
+                .collect()),
+            Err(e) => Err(MyError::from_error(e, "db : get friend ids")),
+        }
+    }
+
     pub async fn find_users_with_substring(&self, name: String) -> Result<Cursor<User>, Error> {
         let filter = doc! {
             "username":{
@@ -339,19 +699,17 @@ impl Db {
         }
     }
 
-    pub async fn chat_exists(&self, chat_id: impl IntoObjectId) -> bool {
-        let id = chat_id.into_object_id();
-        let res = self.chats.find_one(doc! {"_id":id}).await;
+    pub async fn find_chat(&self, chat_id: impl IntoObjectId) -> Option<Chat> {
+        let res = self
+            .chats
+            .find_one(doc! {"_id":chat_id.into_object_id()})
+            .await;
         match res {
-            Ok(Some(_)) => true,
-            Ok(None) => {
-                error!("chat does not exist");
-                false
-            }
+            Ok(c) => c,
             Err(e) => {
-                let err = MyError::from_error(e, "db : chat exists");
+                let err = MyError::from_error(e, "db : find chat");
                 error!("{}", err);
-                false
+                None
             }
         }
     }
@@ -580,7 +938,7 @@ impl Db {
 
     // ========== Messages Collection ==========
     pub async fn find_message(&self,id: impl IntoObjectId) -> Option<DirectMessage>{
-        let res = self.messages.find_one(doc! {"_id":id.into_object_id()}).await;
+        let res = self.messages.find_one(doc! {"_id":id.into_object_id(),"event":{"$exists":false}}).await;
         match res {
             Ok(m) => {
                 m
@@ -592,28 +950,303 @@ impl Db {
         }
     }
 
+    // `viewer` does not get back the messages they deleted for themselves
     pub async fn get_messages_with_chat_id(
         &self,
         chat_id: ObjectId,
-    ) -> Result<Vec<DirectMessage>, MyError> {
-        let res = self.messages.find(doc! {"chat_id":chat_id}).await;
+        viewer: ObjectId,
+        page: &PageRequest,
+    ) -> Result<Page<ChatMessage>, MyError> {
+        let timeline = self
+            .messages
+            .clone_with_type::<TimelineEntry<DirectMessage>>();
+        let filter = doc! {"chat_id":chat_id,"hidden_for":{"$ne":viewer}};
+        let page = self.paginate(&timeline, filter, page).await?;
+        Ok(page.map(|m| ChatMessage::from(m).for_clients()))
+    }
+
+    /// Replaces the content of the message `target` points at if `editor` wrote it after `since`.
+    /// The old content is appended to `edits`, in the same write so concurrent edits lose nothing.
+    /// None when no such message matches.
+    pub async fn edit_message(
+        &self,
+        target: MessageRef,
+        editor: ObjectId,
+        content: String,
+        since: DateTime,
+    ) -> Result<Option<ChatMessage>, MyError> {
+        let mut filter = doc! {
+            "_id":target.message_id(),
+            "from_id":editor,
+            "event":{"$exists":false},
+            "deleted_at":{"$exists":false},
+            "created_at":{"$gte":since}
+        };
+        let now = DateTime::now();
+        let update = vec![doc! {
+            "$set":{
+                "edits":{"$concatArrays":[
+                    {"$ifNull":["$edits",[]]},
+                    [{
+                        "content":"$content",
+                        "created_at":{"$ifNull":["$edited_at","$created_at"]}
+                    }]
+                ]},
+                "content":{"$literal":content},
+                "edited_at":now
+            }
+        }];
+        let res = match target {
+            MessageRef::Direct { chat_id, .. } => {
+                filter.insert("chat_id", chat_id);
+                self.messages
+                    .find_one_and_update(filter, update)
+                    .return_document(ReturnDocument::After)
+                    .await
+                    .map(|m| m.map(ChatMessage::Direct))
+            }
+            MessageRef::Group { group_id, .. } => {
+                filter.insert("group_id", group_id);
+                self.group_messages
+                    .find_one_and_update(filter, update)
+                    .return_document(ReturnDocument::After)
+                    .await
+                    .map(|m| m.map(ChatMessage::Group))
+            }
+        };
         match res {
-            Ok(mut c) => {
-                let mut messages = vec![];
-                while let Some(Ok(m)) = c.next().await {
-                    messages.push(m);
-                }
-                Ok(messages)
+            Ok(m) => Ok(m),
+            Err(e) => Err(MyError::from_error(e, "db : edit message")),
+        }
+    }
+
+    // Delete for me. False when the message is not in that chat or group.
+    pub async fn hide_message(&self, target: MessageRef, user: ObjectId) -> Result<bool, MyError> {
+        let update = doc! {"$addToSet":{"hidden_for":user}};
+        let res = match target {
+            MessageRef::Direct {
+                chat_id,
+                message_id,
+            } => {
+                self.messages
+                    .update_one(doc! {"_id":message_id,"chat_id":chat_id}, update)
+                    .await
+            }
+            MessageRef::Group {
+                group_id,
+                message_id,
+            } => {
+                self.group_messages
+                    .update_one(doc! {"_id":message_id,"group_id":group_id}, update)
+                    .await
+            }
+        };
+        match res {
+            Ok(r) => Ok(r.matched_count == 1),
+            Err(e) => Err(MyError::from_error(e, "db : hide message")),
+        }
+    }
+
+    /// Adds or takes back `user`'s `emoji` reaction. Doing either twice changes nothing.
+    /// None when the message is not in that chat or group, or was deleted for everyone.
+    /// Adding past MAX_REACTIONS_PER_USER distinct reactions is an invalid error.
+    pub async fn set_reaction(
+        &self,
+        target: MessageRef,
+        reaction: Reaction,
+        add: bool,
+    ) -> Result<Option<ChatMessage>, MyError> {
+        let mut filter = doc! {
+            "_id":target.message_id(),
+            "event":{"$exists":false},
+            "deleted_at":{"$exists":false}
+        };
+        let user = reaction.user_id;
+        let reaction = doc! {"emoji":reaction.emoji,"user_id":user};
+        let mut scope = filter.clone();
+        let update = if add {
+            // re-adding one it already has stays fine at the cap
+            filter.insert(
+                "$or",
+                vec![
+                    doc! {"reactions":{"$elemMatch":reaction.clone()}},
+                    doc! {"$expr":{"$lt":[
+                        {"$size":{"$filter":{
+                            "input":{"$ifNull":["$reactions",[]]},
+                            "cond":{"$eq":["$$this.user_id",user]}
+                        }}},
+                        MAX_REACTIONS_PER_USER
+                    ]}},
+                ],
+            );
+            doc! {"$addToSet":{"reactions":reaction}}
+        } else {
+            doc! {"$pull":{"reactions":reaction}}
+        };
+        let res = match target {
+            MessageRef::Direct { chat_id, .. } => {
+                filter.insert("chat_id", chat_id);
+                scope.insert("chat_id", chat_id);
+                self.messages
+                    .find_one_and_update(filter, update)
+                    .return_document(ReturnDocument::After)
+                    .await
+                    .map(|m| m.map(ChatMessage::Direct))
             }
-            Err(e) => Err(MyError::from_error(e, "db : get messages with chat id")),
+            MessageRef::Group { group_id, .. } => {
+                filter.insert("group_id", group_id);
+                scope.insert("group_id", group_id);
+                self.group_messages
+                    .find_one_and_update(filter, update)
+                    .return_document(ReturnDocument::After)
+                    .await
+                    .map(|m| m.map(ChatMessage::Group))
+            }
+        };
+        let message = match res {
+            Ok(m) => m,
+            Err(e) => return Err(MyError::from_error(e, "db : set reaction")),
+        };
+        if message.is_some() || !add {
+            return Ok(message);
+        }
+        // tell a missing message apart from one this user is out of reactions on
+        let found = match target {
+            MessageRef::Direct { .. } => self.messages.count_documents(scope).await,
+            MessageRef::Group { .. } => self.group_messages.count_documents(scope).await,
+        };
+        match found {
+            Ok(0) => Ok(None),
+            Ok(_) => Err(MyError::invalid(
+                format!(
+                    "you can leave at most {} reactions on a message",
+                    MAX_REACTIONS_PER_USER
+                ),
+                "db : set reaction".to_string(),
+            )),
+            Err(e) => Err(MyError::from_error(e, "db : set reaction")),
+        }
+    }
+
+    /// Delete for everyone. Wipes the content and edit history of a message sent after `since`,
+    /// and keeps the rest as a tombstone so the timeline and cursors stay intact.
+    /// None when no such message matches or it is already deleted.
+    pub async fn tombstone_message(
+        &self,
+        target: MessageRef,
+        deleted_by: ObjectId,
+        since: DateTime,
+    ) -> Result<Option<ChatMessage>, MyError> {
+        let mut filter = doc! {
+            "_id":target.message_id(),
+            "event":{"$exists":false},
+            "deleted_at":{"$exists":false},
+            "created_at":{"$gte":since}
+        };
+        let update = doc! {
+            "$set":{
+                "content":"",
+                "deleted_by":deleted_by,
+                "deleted_at":DateTime::now()
+            },
+            "$unset":{"edits":"","edited_at":"","reactions":""}
+        };
+        let res = match target {
+            MessageRef::Direct { chat_id, .. } => {
+                filter.insert("chat_id", chat_id);
+                self.messages
+                    .find_one_and_update(filter, update)
+                    .return_document(ReturnDocument::After)
+                    .await
+                    .map(|m| m.map(ChatMessage::Direct))
+            }
+            MessageRef::Group { group_id, .. } => {
+                filter.insert("group_id", group_id);
+                self.group_messages
+                    .find_one_and_update(filter, update)
+                    .return_document(ReturnDocument::After)
+                    .await
+                    .map(|m| m.map(ChatMessage::Group))
+            }
+        };
+        let deleted = match res {
+            Ok(m) => m,
+            Err(e) => return Err(MyError::from_error(e, "db : tombstone message 1")),
+        };
+        if deleted.is_some() {
+            self.replace_last_message(target).await?;
+        }
+        Ok(deleted)
+    }
+
+    // When the deleted message was the one the chat list shows, point it at the latest
+    // message that is still there, or at nothing
+    async fn replace_last_message(&self, target: MessageRef) -> Result<(), MyError> {
+        let latest = doc! {"event":{"$exists":false},"deleted_at":{"$exists":false}};
+        let sort = doc! {"_id":-1};
+        let res = match target {
+            MessageRef::Direct {
+                chat_id,
+                message_id,
+            } => {
+                let mut filter = latest;
+                filter.insert("chat_id", chat_id);
+                let previous = self.messages.find_one(filter).sort(sort).await?;
+                let update = match previous.and_then(|m| m.id) {
+                    Some(id) => doc! {"$set":{"last_updated_message":id}},
+                    None => doc! {"$unset":{"last_updated_message":""}},
+                };
+                self.chats
+                    .update_one(
+                        doc! {"_id":chat_id,"last_updated_message":message_id},
+                        update,
+                    )
+                    .await
+            }
+            MessageRef::Group {
+                group_id,
+                message_id,
+            } => {
+                let mut filter = latest;
+                filter.insert("group_id", group_id);
+                filter.insert("thread_id", doc! {"$exists":false});
+                let previous = self.group_messages.find_one(filter).sort(sort).await?;
+                let update = match previous.and_then(|m| m.id) {
+                    Some(id) => doc! {"$set":{"last_updated_message":id}},
+                    None => doc! {"$unset":{"last_updated_message":""}},
+                };
+                self.groups
+                    .update_one(
+                        doc! {"_id":group_id,"last_updated_message":message_id},
+                        update,
+                    )
+                    .await
+            }
+        };
+        match res {
+            Ok(_) => Ok(()),
+            Err(e) => Err(MyError::from_error(e, "db : tombstone message 2")),
         }
     }
 
-    pub async fn add_message_to_db(&self, msg: ChatMessage) -> Option<InsertOneResult> {
+    /// Stores the message and returns it with its `_id` set. If the sender already used the
+    /// same `client_msg_id` nothing is inserted, the original message comes back with `true`.
+    pub async fn add_message_to_db(&self, msg: ChatMessage) -> Result<(ChatMessage, bool), MyError> {
         match msg {
-            ChatMessage::Direct(msg) => {
+            ChatMessage::Direct(mut msg) => {
                 if msg.from_id == msg.to_id {
-                    return None;
+                    return Err(MyError::invalid(
+                        "cannot send a message to yourself",
+                        "db : add message to db",
+                    ));
+                }
+                // A retry spotted here does not use up a seq. One that fails to insert, or two copies
+                // of a send racing past this check, still burns the seq it got and leaves a gap.
+                if let Some(original) = self.find_retry(&self.messages, msg.from_id, &msg.client_msg_id).await? {
+                    return Ok((ChatMessage::Direct(original), true));
+                }
+                if let Some(chat_id) = msg.chat_id {
+                    msg.seq = Some(self.next_seq(&format!("chat:{}", chat_id)).await?);
                 }
                 let res = self.messages.insert_one(&msg).await;
                 match res {
@@ -624,29 +1257,156 @@ impl Db {
                         let update = doc! {
                             "$set":{"last_updated_message":r.inserted_id.clone()}
                         };
-                        let res = self.chats.update_one(query, update).await.unwrap();
-                        info!("{:?}", res);
-                        Some(r)
+                        match self.chats.update_one(query, update).await {
+                            Ok(res) => info!("{:?}", res),
+                            Err(e) => error!("{}", e),
+                        }
+                        msg.id = r.inserted_id.as_object_id();
+                        Ok((ChatMessage::Direct(msg), false))
                     }
-                    Err(e) => {
-                        error!("{}", e.to_string());
-                        None
+                    Err(e) if is_duplicate_key(&e) => {
+                        let filter = doc! {
+                            "from_id":msg.from_id,
+                            "client_msg_id":msg.client_msg_id
+                        };
+                        match self.messages.find_one(filter).await {
+                            Ok(Some(original)) => Ok((ChatMessage::Direct(original), true)),
+                            Ok(None) => Err(MyError::new(
+                                "duplicate message vanished",
+                                "db : add message to db 1",
+                            )),
+                            Err(e) => Err(MyError::from_error(e, "db : add message to db 2")),
+                        }
                     }
+                    Err(e) => Err(MyError::from_error(e, "db : add message to db 3")),
                 }
             }
-            ChatMessage::Group(m) => {
-                let res = self.group_messages.insert_one(m).await;
+            ChatMessage::Group(mut m) => {
+                if let Some(original) = self.find_retry(&self.group_messages, m.from_id, &m.client_msg_id).await? {
+                    return Ok((ChatMessage::Group(original), true));
+                }
+                // replies are counted per thread so the group timeline has no holes
+                let counter = match (m.thread_id, m.group_id) {
+                    (Some(thread_id), _) => Some(format!("thread:{}", thread_id)),
+                    (None, Some(group_id)) => Some(format!("group:{}", group_id)),
+                    (None, None) => None,
+                };
+                if let Some(counter) = counter {
+                    m.seq = Some(self.next_seq(&counter).await?);
+                }
+                let res = self.group_messages.insert_one(&m).await;
                 match res {
-                    Ok(r) => Some(r),
-                    Err(e) => {
-                        error!("{}", e.to_string());
-                        None
+                    // a reply in a thread bumps its root instead of the group
+                    Ok(r) if m.thread_id.is_some() => {
+                        let query = doc! {
+                            "_id":m.thread_id,
+                        };
+                        let update = doc! {
+                            "$inc":{"reply_count":1},
+                            "$set":{"last_reply_at":m.created_at}
+                        };
+                        if let Err(e) = self.group_messages.update_one(query, update).await {
+                            error!("{}", e);
+                        }
+                        m.id = r.inserted_id.as_object_id();
+                        Ok((ChatMessage::Group(m), false))
+                    }
+                    Ok(r) => {
+                        let query = doc! {
+                            "_id":m.group_id,
+                        };
+                        let update = doc! {
+                            "$set":{"last_updated_message":r.inserted_id.clone()}
+                        };
+                        if let Err(e) = self.groups.update_one(query, update).await {
+                            error!("{}", e);
+                        }
+                        m.id = r.inserted_id.as_object_id();
+                        Ok((ChatMessage::Group(m), false))
+                    }
+                    Err(e) if is_duplicate_key(&e) => {
+                        let filter = doc! {
+                            "from_id":m.from_id,
+                            "client_msg_id":m.client_msg_id
+                        };
+                        match self.group_messages.find_one(filter).await {
+                            Ok(Some(original)) => Ok((ChatMessage::Group(original), true)),
+                            Ok(None) => Err(MyError::new(
+                                "duplicate message vanished",
+                                "db : add message to db 4",
+                            )),
+                            Err(e) => Err(MyError::from_error(e, "db : add message to db 5")),
+                        }
+                    }
+                    Err(e) => Err(MyError::from_error(e, "db : add message to db 6")),
+                }
+            }
+            // system messages share the timeline but are not what the chat list shows as latest
+            ChatMessage::System(mut m) => {
+                let (coll, counter) = match (m.chat_id, m.group_id) {
+                    (Some(chat_id), _) => (
+                        self.messages.clone_with_type::<SystemMessage>(),
+                        format!("chat:{}", chat_id),
+                    ),
+                    (None, Some(group_id)) => (
+                        self.group_messages.clone_with_type::<SystemMessage>(),
+                        format!("group:{}", group_id),
+                    ),
+                    (None, None) => {
+                        return Err(MyError::new(
+                            "system message needs a chat_id or group_id",
+                            "db : add message to db 7",
+                        ))
+                    }
+                };
+                m.seq = Some(self.next_seq(&counter).await?);
+                match coll.insert_one(&m).await {
+                    Ok(r) => {
+                        m.id = r.inserted_id.as_object_id();
+                        Ok((ChatMessage::System(m), false))
                     }
+                    Err(e) => Err(MyError::from_error(e, "db : add message to db 8")),
                 }
             }
         }
     }
 
+    // The message a sender already stored under `client_msg_id`, if any
+    async fn find_retry<T>(
+        &self,
+        coll: &Collection<T>,
+        from_id: Option<ObjectId>,
+        client_msg_id: &Option<String>,
+    ) -> Result<Option<T>, MyError>
+    where
+        T: DeserializeOwned + Send + Sync,
+    {
+        match retry_filter(from_id, client_msg_id) {
+            Some(filter) => Ok(coll.find_one(filter).await?),
+            None => Ok(None),
+        }
+    }
+
+    // The `_id` of the message at `seq` in a chat or group timeline
+    pub async fn find_message_id_by_seq(
+        &self,
+        conversation: ConversationRef,
+        seq: i64,
+    ) -> Result<Option<ObjectId>, MyError> {
+        let (coll, filter) = match conversation {
+            ConversationRef::Direct { chat_id } => (
+                self.messages.clone_with_type::<Document>(),
+                doc! {"chat_id":chat_id,"seq":seq},
+            ),
+            ConversationRef::Group { group_id } => (
+                self.group_messages.clone_with_type::<Document>(),
+                doc! {"group_id":group_id,"thread_id":{"$exists":false},"seq":seq},
+            ),
+        };
+        let message = coll.find_one(filter).await?;
+        Ok(message.and_then(|m| m.get_object_id("_id").ok()))
+    }
+
     //========== Group Collection ==========
     pub async fn find_group(&self, group_id: impl IntoObjectId) -> Option<Group> {
         let id = group_id.into_object_id();
@@ -663,125 +1423,724 @@ impl Db {
         }
     }
 
-    pub async fn group_exists(&self, group_id: impl IntoObjectId) -> bool {
+    // Pages through the groups `id` is in, newest group first
+    pub async fn get_groups(
+        &self,
+        id: impl IntoObjectId,
+        page: &PageRequest,
+    ) -> Result<Page<GroupConversation>, MyError> {
+        let viewer = id.into_object_id();
+        let page = self.paginate(&self.groups, doc! {"members":viewer}, page).await?;
+        let mut groups = vec![];
+        for group in page.items {
+            groups.push(group.convert(viewer, self).await);
+        }
+        Ok(Page {
+            items: groups,
+            next_cursor: page.next_cursor,
+            next_seq: None,
+        })
+    }
+
+    // Regular messages only, system messages in the same collection are skipped
+    pub async fn find_group_message(&self, id: impl IntoObjectId) -> Option<GroupMessage> {
         let res = self
-            .groups
-            .find_one(doc! {"_id":group_id.into_object_id()})
+            .group_messages
+            .find_one(doc! {"_id":id.into_object_id(),"event":{"$exists":false}})
             .await;
         match res {
-            Ok(Some(_)) => true,
-            Ok(None) => {
-                error!("group does not exist");
-                false
-            }
+            Ok(m) => m,
             Err(e) => {
-                let err = MyError::from_error(e, "db : group exists");
-                error!("{}", err);
-                false
+                error!("{}", e);
+                None
             }
         }
     }
 
-    pub async fn create_group_chat<T: IntoObjectId>(
+    pub async fn get_group_messages(
+        &self,
+        group_id: ObjectId,
+        viewer: ObjectId,
+        page: &PageRequest,
+    ) -> Result<Page<ChatMessage>, MyError> {
+        let timeline = self
+            .group_messages
+            .clone_with_type::<TimelineEntry<GroupMessage>>();
+        // thread replies are only listed under their root
+        let filter = doc! {
+            "group_id":group_id,
+            "thread_id":{"$exists":false},
+            "hidden_for":{"$ne":viewer}
+        };
+        let page = self.paginate(&timeline, filter, page).await?;
+        Ok(page.map(|m| ChatMessage::from(m).for_clients()))
+    }
+
+    pub async fn get_thread_replies(
+        &self,
+        thread: ThreadRef,
+        viewer: ObjectId,
+        page: &PageRequest,
+    ) -> Result<Page<ChatMessage>, MyError> {
+        let filter = doc! {
+            "group_id":thread.group_id,
+            "thread_id":thread.thread_id,
+            "hidden_for":{"$ne":viewer}
+        };
+        let page = self.paginate(&self.group_messages, filter, page).await?;
+        Ok(page.map(|m| ChatMessage::Group(m).for_clients()))
+    }
+
+    pub async fn create_group_chat(
         &self,
-        id: T,
-        members: HashSet<T>,
+        id: impl IntoObjectId,
+        details: NewGroup,
     ) -> Option<InsertOneResult> {
-        let mut admin = HashSet::new();
-        admin.insert(id.into_object_id());
+        let creator = id.into_object_id();
         let mut users: HashSet<ObjectId> = HashSet::new();
-        for user in members {
-            users.insert(user.into_object_id());
+        users.insert(creator);
+        for user in details.members.iter() {
+            users.insert(user.clone().into_object_id());
         }
-        let group = Group::new(admin, users);
+        let group = Group::new(creator, users, details);
         let res = self.groups.insert_one(group).await;
         match res {
             Ok(r) => Some(r),
             Err(e) => {
-                error!("{}", e.to_string());
+                error!("{}", e);
                 None
             }
         }
     }
 
-    pub async fn check_admin<T>(&self, admin: T, group_id: ObjectId) -> bool
-    where
-        T: IntoObjectId,
-    {
-        let filter = doc! {
-            "$and":[
-                {"_id":group_id.into_object_id()},
-                {"admins": {
-                    "$in":[admin.into_object_id()]
-                }}
-            ]
+    pub async fn update_group_details(&self, update: GroupUpdate) -> Result<Option<Group>, MyError> {
+        let mut set = doc! {"updated_at":DateTime::now()};
+        if let Some(name) = update.name {
+            set.insert("name", name);
+        }
+        if let Some(description) = update.description {
+            set.insert("description", description);
+        }
+        if let Some(avatar) = update.avatar {
+            set.insert("avatar", avatar);
+        }
+        if let Some(settings) = update.settings {
+            match to_bson(&settings) {
+                Ok(s) => {
+                    set.insert("settings", s);
+                }
+                Err(e) => return Err(MyError::from_error(e, "db : update group details 1")),
+            }
+        }
+        let res = self
+            .groups
+            .find_one_and_update(doc! {"_id":update.group_id}, doc! {"$set":set})
+            .return_document(ReturnDocument::After)
+            .await;
+        match res {
+            Ok(g) => Ok(g),
+            Err(e) => Err(MyError::from_error(e, "db : update group details 2")),
+        }
+    }
+
+    // `role` is validated by the caller, this only writes it. Plain members have no entry.
+    pub async fn set_group_role(
+        &self,
+        group_id: ObjectId,
+        user: ObjectId,
+        role: GroupRole,
+    ) -> Result<Option<Group>, MyError> {
+        let key = format!("roles.{}", user.to_hex());
+        let update = match role {
+            GroupRole::Member => doc! {
+                "$unset":{key:""},
+                "$set":{"updated_at":DateTime::now()}
+            },
+            role => {
+                let role = to_bson(&role).map_err(|e| MyError::from_error(e, "db : set group role 1"))?;
+                doc! {
+                    "$set":{key:role,"updated_at":DateTime::now()}
+                }
+            }
         };
-        let res = self.groups.find_one(filter).await;
+        let res = self
+            .groups
+            .find_one_and_update(doc! {"_id":group_id,"members":user}, update)
+            .return_document(ReturnDocument::After)
+            .await;
         match res {
-            Ok(_) => true,
-            Err(e) => {
-                error!("{}", e.to_string());
-                false
+            Ok(g) => Ok(g),
+            Err(e) => Err(MyError::from_error(e, "db : set group role 2")),
+        }
+    }
+
+    // Swaps both roles in one write so the group never has zero or two owners.
+    // Matches nothing when `from` is no longer the owner or `to` left the group.
+    pub async fn transfer_group_ownership(
+        &self,
+        group_id: ObjectId,
+        from: ObjectId,
+        to: ObjectId,
+    ) -> Result<Option<Group>, MyError> {
+        let from_key = format!("roles.{}", from.to_hex());
+        let to_key = format!("roles.{}", to.to_hex());
+        let filter = doc! {
+            "_id":group_id,
+            from_key.clone():"owner",
+            "members":to
+        };
+        let update = doc! {
+            "$set":{
+                from_key:"admin",
+                to_key:"owner",
+                "updated_at":DateTime::now()
             }
+        };
+        let res = self
+            .groups
+            .find_one_and_update(filter, update)
+            .return_document(ReturnDocument::After)
+            .await;
+        match res {
+            Ok(g) => Ok(g),
+            Err(e) => Err(MyError::from_error(e, "db : transfer group ownership")),
         }
     }
+
     pub async fn add_or_remove_members<T>(
         &self,
         admin: T,
         group_id: T,
         members: Vec<String>,
         action: &str,
-    ) -> Result<(), MyError>
+    ) -> Result<Group, MyError>
     where
         T: IntoObjectId,
     {
         let grp_id = group_id.into_object_id();
-        if !self.check_admin(admin, grp_id.clone()).await {
+        let admin = admin.into_object_id();
+        let mut users = vec![];
+        for user in members {
+            match ObjectId::parse_str(&user) {
+                Ok(u) => users.push(u),
+                Err(e) => return Err(MyError::from_error(e, "db : add or remove member 1")),
+            }
+        }
+        let group = match self.find_group(grp_id).await {
+            Some(g) => g,
+            None => {
+                return Err(MyError::new(
+                    "group does not exist",
+                    "db : add or remove member 2",
+                ))
+            }
+        };
+        // adding follows the group's who_can_add_members setting, removing needs an admin
+        // who outranks everyone being removed
+        let allowed = match action {
+            "add" => group.can(admin, GroupPermission::AddMembers),
+            _ => {
+                group.can(admin, GroupPermission::RemoveMembers)
+                    && users.iter().all(|m| group.outranks(admin, *m))
+            }
+        };
+        if !allowed {
             return Err(MyError::new(
-                "only admin are allowed to add or remove members",
+                "you are not allowed to add or remove members",
                 "db : add or remove member function",
             ));
         }
-        let filter = doc! {
-            "_id":grp_id.into_object_id()
-        };
-        let mut users = vec![];
-        for user in members {
-            users.push(user.into_object_id());
-        }
-        let update: Document;
-        match action {
+        let res = match action {
             "add" => {
-                update = doc! {
-                    "$push":{
-                        "members":users
-                    }
-                };
-            }
-            "remove" => {
-                update = doc! {
-                    "$pull":{
-                        "members":users
+                if users.iter().any(|u| group.banned.contains(u)) {
+                    return Err(MyError::new(
+                        "banned users cannot be added until they are unbanned",
+                        "db : add or remove member 3",
+                    ));
+                }
+                let update = doc! {
+                    "$addToSet":{
+                        "members":{"$each":&users}
                     }
                 };
+                self.groups
+                    .find_one_and_update(doc! {"_id":grp_id}, update)
+                    .return_document(ReturnDocument::After)
+                    .await
+                    .map_err(MyError::from)
             }
+            "remove" => self.remove_group_members(grp_id, &users, false).await,
             _ => {
                 return Err(MyError::new(
                     "invalid actions, try again",
                     "db : add or remove member function",
                 ))
             }
-        }
-        let res = self.groups.find_one_and_update(filter, update).await;
+        };
         match res {
-            Ok(_) => Ok(()),
+            Ok(Some(g)) => Ok(g),
+            Ok(None) => Err(MyError::new(
+                "group does not exist",
+                "db : add or remove member 4",
+            )),
             Err(e) => Err(MyError::new(
-                e.to_string(),
-                "db : add or remove member".to_string(),
+                e.error(),
+                "db : add or remove member",
             )),
         }
     }
 
+    // Takes users out of the group together with their role and mute.
+    // With `ban` they also go on the ban list so they cannot come back.
+    pub async fn remove_group_members(
+        &self,
+        group_id: ObjectId,
+        users: &[ObjectId],
+        ban: bool,
+    ) -> Result<Option<Group>, MyError> {
+        if users.is_empty() {
+            return Ok(self.find_group(group_id).await);
+        }
+        let mut unset = Document::new();
+        for user in users {
+            unset.insert(format!("roles.{}", user.to_hex()), "");
+            unset.insert(format!("muted.{}", user.to_hex()), "");
+        }
+        let mut update = doc! {
+            "$pull":{"members":{"$in":users}},
+            "$unset":unset
+        };
+        if ban {
+            update.insert("$addToSet", doc! {"banned":{"$each":users}});
+        }
+        let res = self
+            .groups
+            .find_one_and_update(doc! {"_id":group_id}, update)
+            .return_document(ReturnDocument::After)
+            .await;
+        match res {
+            Ok(g) => Ok(g),
+            Err(e) => Err(MyError::from_error(e, "db : remove group members")),
+        }
+    }
+
+    pub async fn unban_group_member(
+        &self,
+        group_id: ObjectId,
+        user: ObjectId,
+    ) -> Result<Option<Group>, MyError> {
+        let res = self
+            .groups
+            .find_one_and_update(
+                doc! {"_id":group_id},
+                doc! {"$pull":{"banned":user}},
+            )
+            .return_document(ReturnDocument::After)
+            .await;
+        match res {
+            Ok(g) => Ok(g),
+            Err(e) => Err(MyError::from_error(e, "db : unban group member")),
+        }
+    }
+
+    // `until` of None lifts the mute
+    pub async fn set_group_mute(
+        &self,
+        group_id: ObjectId,
+        user: ObjectId,
+        until: Option<DateTime>,
+    ) -> Result<Option<Group>, MyError> {
+        let key = format!("muted.{}", user.to_hex());
+        let update = match until {
+            Some(until) => doc! {"$set":{key:until}},
+            None => doc! {"$unset":{key:""}},
+        };
+        let res = self
+            .groups
+            .find_one_and_update(doc! {"_id":group_id,"members":user}, update)
+            .return_document(ReturnDocument::After)
+            .await;
+        match res {
+            Ok(g) => Ok(g),
+            Err(e) => Err(MyError::from_error(e, "db : set group mute")),
+        }
+    }
+
+    // None when the group is gone or the user is banned from it
+    pub async fn add_group_member(
+        &self,
+        group_id: ObjectId,
+        user: ObjectId,
+    ) -> Result<Option<Group>, MyError> {
+        let res = self
+            .groups
+            .find_one_and_update(
+                doc! {"_id":group_id,"banned":{"$ne":user}},
+                doc! {"$addToSet":{"members":user}},
+            )
+            .return_document(ReturnDocument::After)
+            .await;
+        match res {
+            Ok(g) => Ok(g),
+            Err(e) => Err(MyError::from_error(e, "db : add group member")),
+        }
+    }
+
+    // ========== Group Invites Collection ==========
+
+    pub async fn create_invite(&self, invite: GroupInvite) -> Result<GroupInvite, MyError> {
+        let res = self.group_invites.insert_one(&invite).await;
+        match res {
+            Ok(r) => Ok(GroupInvite {
+                id: r.inserted_id.as_object_id(),
+                ..invite
+            }),
+            Err(e) => Err(MyError::from_error(e, "db : create invite")),
+        }
+    }
+
+    pub async fn find_invite(&self, token: &str) -> Option<GroupInvite> {
+        let res = self.group_invites.find_one(doc! {"token":token}).await;
+        match res {
+            Ok(i) => i,
+            Err(e) => {
+                error!("{}", e);
+                None
+            }
+        }
+    }
+
+    pub async fn get_group_invites(&self, group_id: ObjectId) -> Result<Vec<GroupInvite>, MyError> {
+        let mut cursor = self
+            .group_invites
+            .find(doc! {"group_id":group_id,"revoked":false})
+            .sort(doc! {"created_at":-1})
+            .await?;
+        let mut invites = vec![];
+        while let Some(invite) = cursor.next().await {
+            invites.push(invite?);
+        }
+        Ok(invites)
+    }
+
+    pub async fn use_invite(&self, token: &str) -> Result<Option<GroupInvite>, MyError> {
+        self.take_invite_use(doc! {"token":token}).await
+    }
+
+    pub async fn use_invite_with_id(&self, id: ObjectId) -> Result<Option<GroupInvite>, MyError> {
+        self.take_invite_use(doc! {"_id":id}).await
+    }
+
+    // Gives back a use taken for a join that did not go through
+    pub async fn release_invite_use(&self, id: ObjectId) -> Result<(), MyError> {
+        let res = self
+            .group_invites
+            .update_one(doc! {"_id":id,"uses":{"$gt":0}}, doc! {"$inc":{"uses":-1}})
+            .await;
+        match res {
+            Ok(_) => Ok(()),
+            Err(e) => Err(MyError::from_error(e, "db : release invite use")),
+        }
+    }
+
+    // Counts one use of the invite, in the same write that checks it is still usable
+    // so two people cannot both take the last use. None when it is revoked, expired or used up.
+    async fn take_invite_use(&self, mut filter: Document) -> Result<Option<GroupInvite>, MyError> {
+        filter.extend(doc! {
+            "revoked":false,
+            "$and":[
+                {"$or":[
+                    {"expires_at":{"$exists":false}},
+                    {"expires_at":{"$gt":DateTime::now()}}
+                ]},
+                {"$or":[
+                    {"max_uses":{"$exists":false}},
+                    {"$expr":{"$lt":["$uses","$max_uses"]}}
+                ]}
+            ]
+        });
+        let res = self
+            .group_invites
+            .find_one_and_update(filter, doc! {"$inc":{"uses":1}})
+            .return_document(ReturnDocument::After)
+            .await;
+        match res {
+            Ok(i) => Ok(i),
+            Err(e) => Err(MyError::from_error(e, "db : use invite")),
+        }
+    }
+
+    pub async fn find_invite_with_id(&self, id: ObjectId) -> Option<GroupInvite> {
+        let res = self.group_invites.find_one(doc! {"_id":id}).await;
+        match res {
+            Ok(i) => i,
+            Err(e) => {
+                error!("{}", e);
+                None
+            }
+        }
+    }
+
+    pub async fn revoke_invite(&self, id: ObjectId) -> Result<(), MyError> {
+        let res = self
+            .group_invites
+            .update_one(doc! {"_id":id}, doc! {"$set":{"revoked":true}})
+            .await;
+        match res {
+            Ok(_) => Ok(()),
+            Err(e) => Err(MyError::from_error(e, "db : revoke invite")),
+        }
+    }
+
+    // ========== Group Join Requests Collection ==========
+
+    // Asking again while a request is pending returns the existing one
+    pub async fn create_join_request(
+        &self,
+        request: GroupJoinRequest,
+    ) -> Result<GroupJoinRequest, MyError> {
+        let res = self.group_join_requests.insert_one(&request).await;
+        match res {
+            Ok(r) => Ok(GroupJoinRequest {
+                id: r.inserted_id.as_object_id(),
+                ..request
+            }),
+            Err(e) if is_duplicate_key(&e) => {
+                let filter = doc! {
+                    "group_id":request.group_id,
+                    "from_id":request.from_id,
+                    "status":"pending"
+                };
+                match self.group_join_requests.find_one(filter).await? {
+                    Some(existing) => Ok(existing),
+                    None => Err(MyError::new(
+                        "join request was handled while creating it, try again",
+                        "db : create join request",
+                    )),
+                }
+            }
+            Err(e) => Err(MyError::from_error(e, "db : create join request")),
+        }
+    }
+
+    pub async fn find_join_request(&self, id: ObjectId) -> Option<GroupJoinRequest> {
+        let res = self.group_join_requests.find_one(doc! {"_id":id}).await;
+        match res {
+            Ok(r) => r,
+            Err(e) => {
+                error!("{}", e);
+                None
+            }
+        }
+    }
+
+    pub async fn get_pending_join_requests(
+        &self,
+        group_id: ObjectId,
+    ) -> Result<Vec<GroupJoinRequest>, MyError> {
+        let mut cursor = self
+            .group_join_requests
+            .find(doc! {"group_id":group_id,"status":"pending"})
+            .sort(doc! {"created_at":1})
+            .await?;
+        let mut requests = vec![];
+        while let Some(request) = cursor.next().await {
+            requests.push(request?);
+        }
+        Ok(requests)
+    }
+
+    // None when the request was already approved or rejected, so two admins
+    // handling it at once only take effect once
+    pub async fn resolve_join_request(
+        &self,
+        id: ObjectId,
+        status: &str,
+        handled_by: ObjectId,
+    ) -> Result<Option<GroupJoinRequest>, MyError> {
+        let update = doc! {
+            "$set":{
+                "status":status,
+                "handled_by":handled_by,
+                "handled_at":DateTime::now()
+            }
+        };
+        let res = self
+            .group_join_requests
+            .find_one_and_update(doc! {"_id":id,"status":"pending"}, update)
+            .return_document(ReturnDocument::After)
+            .await;
+        match res {
+            Ok(r) => Ok(r),
+            Err(e) => Err(MyError::from_error(e, "db : resolve join request")),
+        }
+    }
+
+    // ========== Sessions Collection ==========
+
+    pub async fn create_session(&self, session: Session) -> Result<(), MyError> {
+        let res = self.sessions.insert_one(session).await;
+        match res {
+            Ok(r) => {
+                info!("created session : {}", r.inserted_id);
+                Ok(())
+            }
+            Err(e) => Err(MyError::from_error(e, "db : create session")),
+        }
+    }
+
+    pub async fn session_is_active(&self, session_id: impl IntoObjectId) -> bool {
+        let filter = doc! {
+            "_id":session_id.into_object_id(),
+            "revoked":false,
+            "expires_at":{"$gt":DateTime::now()}
+        };
+        let res = self.sessions.find_one(filter).await;
+        match res {
+            Ok(Some(_)) => true,
+            Ok(None) => false,
+            Err(e) => {
+                let err = MyError::from_error(e, "db : session is active");
+                error!("{}", err);
+                false
+            }
+        }
+    }
+
+    pub async fn touch_session(&self, session_id: impl IntoObjectId) {
+        let res = self
+            .sessions
+            .update_one(
+                doc! {"_id":session_id.into_object_id()},
+                doc! {"$set":{"last_seen_at":DateTime::now()}},
+            )
+            .await;
+        if let Err(e) = res {
+            let err = MyError::from_error(e, "db : touch session");
+            error!("{}", err);
+        }
+    }
+
+    pub async fn get_active_sessions(
+        &self,
+        user_id: impl IntoObjectId,
+    ) -> Result<Vec<Session>, MyError> {
+        let filter = doc! {
+            "user_id":user_id.into_object_id(),
+            "revoked":false,
+            "expires_at":{"$gt":DateTime::now()}
+        };
+        let res = self.sessions.find(filter).sort(doc! {"last_seen_at":-1}).await;
+        match res {
+            Ok(mut cursor) => {
+                let mut sessions = vec![];
+                while let Some(Ok(s)) = cursor.next().await {
+                    sessions.push(s);
+                }
+                Ok(sessions)
+            }
+            Err(e) => Err(MyError::from_error(e, "db : get active sessions")),
+        }
+    }
+
+    /// Swaps the current refresh token id of a live session for `new_jti` and returns the id
+    /// the client should hold now. The token it replaced stays good for `grace` and gets the
+    /// session's current id back without another rotation.
+    /// Returns `Ok(None)` when `old_jti` is neither, which means an already rotated token was presented again.
+    pub async fn rotate_session(
+        &self,
+        session_id: impl IntoObjectId,
+        old_jti: &str,
+        new_jti: &str,
+        device: DeviceInfo,
+        grace: Duration,
+    ) -> Result<Option<String>, MyError> {
+        let session_id = session_id.into_object_id();
+        let now = DateTime::now();
+        // a second look only happens when another request rotated the token in between
+        for _ in 0..2 {
+            let session = match self.sessions.find_one(doc! {"_id":session_id}).await {
+                Ok(Some(s)) => s,
+                Ok(None) => return Ok(None),
+                Err(e) => return Err(MyError::from_error(e, "db : rotate session")),
+            };
+            match session.check_refresh(old_jti, now, grace) {
+                RefreshCheck::Replay(current) => return Ok(Some(current)),
+                RefreshCheck::Reuse => return Ok(None),
+                RefreshCheck::Rotate => (),
+            }
+            let filter = doc! {"_id":session_id,"refresh_jti":old_jti};
+            let update = doc! {
+                "$set":{
+                    "refresh_jti":new_jti,
+                    "previous_jti":old_jti,
+                    "rotated_at":now,
+                    "user_agent":&device.user_agent,
+                    "ip":&device.ip,
+                    "last_seen_at":now
+                }
+            };
+            match self.sessions.find_one_and_update(filter, update).await {
+                Ok(Some(_)) => return Ok(Some(new_jti.to_string())),
+                Ok(None) => (),
+                Err(e) => return Err(MyError::from_error(e, "db : rotate session 1")),
+            }
+        }
+        Ok(None)
+    }
+
+    pub async fn revoke_session(&self, session_id: impl IntoObjectId) -> Result<(), MyError> {
+        let res = self
+            .sessions
+            .update_one(
+                doc! {"_id":session_id.into_object_id()},
+                doc! {"$set":{"revoked":true}},
+            )
+            .await;
+        match res {
+            Ok(r) => {
+                info!("revoked session : {:?}", r);
+                Ok(())
+            }
+            Err(e) => Err(MyError::from_error(e, "db : revoke session")),
+        }
+    }
+
+    /// Revokes a session only if it belongs to `user_id`, returns whether one was revoked
+    pub async fn revoke_user_session(
+        &self,
+        user_id: impl IntoObjectId,
+        session_id: impl IntoObjectId,
+    ) -> Result<bool, MyError> {
+        let filter = doc! {
+            "_id":session_id.into_object_id(),
+            "user_id":user_id.into_object_id(),
+            "revoked":false
+        };
+        let res = self
+            .sessions
+            .update_one(filter, doc! {"$set":{"revoked":true}})
+            .await;
+        match res {
+            Ok(r) => Ok(r.modified_count == 1),
+            Err(e) => Err(MyError::from_error(e, "db : revoke user session")),
+        }
+    }
+
+    pub async fn revoke_all_sessions(&self, user_id: impl IntoObjectId) -> Result<u64, MyError> {
+        let res = self
+            .sessions
+            .update_many(
+                doc! {"user_id":user_id.into_object_id(),"revoked":false},
+                doc! {"$set":{"revoked":true}},
+            )
+            .await;
+        match res {
+            Ok(r) => Ok(r.modified_count),
+            Err(e) => Err(MyError::from_error(e, "db : revoke all sessions")),
+        }
+    }
+
     // <============== Clone of Collections ==============>
 
     // pub async fn users(self) -> Arc<Collection<User>> {
@@ -802,4 +2161,402 @@ impl Db {
     // pub async fn groups_messages(self) -> Arc<Collection<GroupMessage>> {
     //     self.group_messages.clone()
     // }
+
+    //========== Read Markers Collection ==========
+    // Where the target sits in its timeline, see Watermark
+    async fn watermark_at(&self, target: MessageRef) -> Result<Watermark, MyError> {
+        let coll = match target {
+            MessageRef::Direct { .. } => self.messages.clone_with_type::<Document>(),
+            MessageRef::Group { .. } => self.group_messages.clone_with_type::<Document>(),
+        };
+        let message = coll
+            .find_one(doc! {"_id":target.message_id()})
+            .projection(doc! {"seq":1,"thread_id":1})
+            .await?;
+        let seq = message
+            .filter(|m| !m.contains_key("thread_id"))
+            .and_then(|m| m.get_i64("seq").ok());
+        Ok(Watermark {
+            message_id: target.message_id(),
+            seq,
+        })
+    }
+
+    // Moves the watermarks in `fields` of `user` up to `to`, never back.
+    // Returns the marker as it was before, None if this is the first one.
+    async fn raise_watermarks(
+        &self,
+        target: MessageRef,
+        to: &Watermark,
+        user: ObjectId,
+        fields: &[&str],
+    ) -> Result<Option<ReadMarker>, MyError> {
+        let filter = match target {
+            MessageRef::Direct { chat_id, .. } => doc! {"user_id":user,"chat_id":chat_id},
+            MessageRef::Group { group_id, .. } => doc! {"user_id":user,"group_id":group_id},
+        };
+        let mut set = doc! {"updated_at":DateTime::now()};
+        for field in fields {
+            set.extend(raise_watermark(field, to));
+        }
+        let update = vec![doc! {"$set":set}];
+        let res = self
+            .read_markers
+            .find_one_and_update(filter.clone(), update.clone())
+            .upsert(true)
+            .return_document(ReturnDocument::Before)
+            .await;
+        match res {
+            Ok(previous) => Ok(previous),
+            // another upsert created the marker first, it exists now
+            Err(e) if is_duplicate_key(&e) => Ok(self
+                .read_markers
+                .find_one_and_update(filter, update)
+                .return_document(ReturnDocument::Before)
+                .await?),
+            Err(e) => Err(MyError::from_error(e, "db : raise watermarks")),
+        }
+    }
+
+    /// Raises the delivered watermark of each of `users` to the target. Returns where the target sits.
+    pub async fn mark_delivered(
+        &self,
+        target: MessageRef,
+        users: &[ObjectId],
+    ) -> Result<Watermark, MyError> {
+        let to = self.watermark_at(target).await?;
+        let updates = users
+            .iter()
+            .map(|user| self.raise_watermarks(target, &to, *user, &["delivered_up_to"]));
+        for res in futures::future::join_all(updates).await {
+            res?;
+        }
+        Ok(to)
+    }
+
+    /// Raises `user`'s delivered watermark to the target
+    pub async fn advance_delivered(
+        &self,
+        target: MessageRef,
+        user: ObjectId,
+    ) -> Result<WatermarkMove, MyError> {
+        let to = self.watermark_at(target).await?;
+        let previous = self
+            .raise_watermarks(target, &to, user, &["delivered_up_to"])
+            .await?;
+        Ok(WatermarkMove {
+            from: previous.and_then(|m| m.delivered()),
+            to,
+        })
+    }
+
+    /// Whether the message is in the chat or group `target` names, system messages included
+    pub async fn message_in_conversation(&self, target: MessageRef) -> Result<bool, MyError> {
+        let res = match target {
+            MessageRef::Direct { chat_id, message_id } => {
+                self.messages
+                    .count_documents(doc! {"_id":message_id,"chat_id":chat_id})
+                    .await
+            }
+            MessageRef::Group { group_id, message_id } => {
+                self.group_messages
+                    .count_documents(doc! {"_id":message_id,"group_id":group_id})
+                    .await
+            }
+        };
+        match res {
+            Ok(n) => Ok(n > 0),
+            Err(e) => Err(MyError::from_error(e, "db : message in conversation")),
+        }
+    }
+
+    /// Reading a message also means it was delivered. Returns how the read watermark moved.
+    pub async fn mark_read(
+        &self,
+        target: MessageRef,
+        user: ObjectId,
+    ) -> Result<WatermarkMove, MyError> {
+        let to = self.watermark_at(target).await?;
+        let previous = self
+            .raise_watermarks(target, &to, user, &["read_up_to", "delivered_up_to"])
+            .await?;
+        Ok(WatermarkMove {
+            from: previous.and_then(|m| m.read()),
+            to,
+        })
+    }
+
+    /// Who sent the messages a watermark move newly covers, leaving out `reader`
+    pub async fn senders_between(
+        &self,
+        target: MessageRef,
+        moved: &WatermarkMove,
+        reader: ObjectId,
+    ) -> Result<Vec<ObjectId>, MyError> {
+        let mut range = vec![up_to_watermark(&moved.to)];
+        if let Some(from) = &moved.from {
+            range.push(after_watermark(from));
+        }
+        let mut filter = doc! {
+            "$and":range,
+            "from_id":{"$ne":reader},
+            "event":{"$exists":false}
+        };
+        let res = match target {
+            MessageRef::Direct { chat_id, .. } => {
+                filter.insert("chat_id", chat_id);
+                self.messages.distinct("from_id", filter).await
+            }
+            MessageRef::Group { group_id, .. } => {
+                filter.insert("group_id", group_id);
+                self.group_messages.distinct("from_id", filter).await
+            }
+        };
+        match res {
+            Ok(ids) => Ok(ids.iter().filter_map(Bson::as_object_id).collect()),
+            Err(e) => Err(MyError::from_error(e, "db : senders between")),
+        }
+    }
+
+    // Messages from others after the user's read watermark. Deleted and hidden messages
+    // and thread replies are not counted. `key` is the chat_id or group_id filter.
+    async fn count_unread<T: Send + Sync>(
+        &self,
+        coll: &Collection<T>,
+        key: Document,
+        user: ObjectId,
+    ) -> Result<u64, MyError> {
+        let mut marker = key.clone();
+        marker.insert("user_id", user);
+        let read = self
+            .read_markers
+            .find_one(marker)
+            .await?
+            .and_then(|m| m.read());
+        let mut filter = key;
+        filter.extend(doc! {
+            "from_id":{"$ne":user},
+            "event":{"$exists":false},
+            "deleted_at":{"$exists":false},
+            "thread_id":{"$exists":false},
+            "hidden_for":{"$ne":user}
+        });
+        if let Some(read) = read {
+            filter.extend(after_watermark(&read));
+        }
+        Ok(coll.count_documents(filter).await?)
+    }
+
+    pub async fn unread_in_chat(&self, user: ObjectId, chat_id: ObjectId) -> Result<u64, MyError> {
+        self.count_unread(&self.messages, doc! {"chat_id":chat_id}, user)
+            .await
+    }
+
+    pub async fn unread_in_group(&self, user: ObjectId, group_id: ObjectId) -> Result<u64, MyError> {
+        self.count_unread(&self.group_messages, doc! {"group_id":group_id}, user)
+            .await
+    }
+
+    pub async fn get_read_markers(&self, group_id: ObjectId) -> Result<Vec<ReadMarker>, MyError> {
+        let mut cursor = self
+            .read_markers
+            .find(doc! {"group_id":group_id})
+            .await?;
+        let mut markers = vec![];
+        while let Some(marker) = cursor.next().await {
+            markers.push(marker?);
+        }
+        Ok(markers)
+    }
+
+    //========== Change Log ==========
+    // Hands out 1, 2, 3, .. for `name`, safe to call from any number of servers at once
+    async fn next_seq(&self, name: &str) -> Result<i64, MyError> {
+        let counter = self
+            .counters
+            .find_one_and_update(doc! {"_id":name}, doc! {"$inc":{"seq":1_i64}})
+            .upsert(true)
+            .return_document(ReturnDocument::After)
+            .await?;
+        match counter.and_then(|c| c.get_i64("seq").ok()) {
+            Some(seq) => Ok(seq),
+            None => Err(MyError::new("counter without a seq", "db : next seq")),
+        }
+    }
+
+    // Readers only go as far as settled_change_seq, see there for why
+    pub async fn record_change(&self, mut change: Change) -> Result<i64, MyError> {
+        change.seq = self.next_seq("changes").await?;
+        match self.changes.insert_one(&change).await {
+            Ok(_) => Ok(change.seq),
+            Err(e) => Err(MyError::from_error(e, "db : record change")),
+        }
+    }
+
+    /// The highest seq with every change up to it in the log. A seq is handed out before its
+    /// change is inserted, so a later change can land first and a client syncing in between would
+    /// skip the earlier one for good. A seq still missing after CHANGE_SETTLE_SECS was never stored.
+    pub async fn settled_change_seq(&self) -> Result<i64, MyError> {
+        let cutoff = DateTime::from_millis(
+            DateTime::now().timestamp_millis() - CHANGE_SETTLE_SECS as i64 * 1000,
+        );
+        // anything missing below a change this old is not coming anymore
+        let floor = self
+            .changes
+            .find_one(doc! {"created_at":{"$lt":cutoff}})
+            .sort(doc! {"seq":-1})
+            .await?
+            .map_or(0, |c| c.seq);
+        let mut cursor = self
+            .changes
+            .clone_with_type::<Document>()
+            .find(doc! {"seq":{"$gt":floor}})
+            .sort(doc! {"seq":1})
+            .projection(doc! {"seq":1})
+            .await?;
+        let mut settled = floor;
+        while let Some(change) = cursor.next().await {
+            match change?.get_i64("seq") {
+                Ok(seq) if seq == settled + 1 => settled = seq,
+                _ => break,
+            }
+        }
+        Ok(settled)
+    }
+
+    // Older changes have expired, a cursor from before this one missed some
+    pub async fn oldest_change_seq(&self) -> Result<Option<i64>, MyError> {
+        let oldest = self.changes.find_one(doc! {}).sort(doc! {"seq":1}).await?;
+        Ok(oldest.map(|c| c.seq))
+    }
+
+    // Where in the log the message was stored, None once that has expired
+    pub async fn change_seq_for_message(&self, message_id: ObjectId) -> Result<Option<i64>, MyError> {
+        let filter = doc! {"event.kind":"message","event.message._id":message_id};
+        let change = self.changes.find_one(filter).await?;
+        Ok(change.map(|c| c.seq))
+    }
+
+    pub async fn get_changes(&self, filter: Document, limit: i64) -> Result<Vec<Change>, MyError> {
+        let mut cursor = self
+            .changes
+            .find(filter)
+            .sort(doc! {"seq":1})
+            .limit(limit)
+            .await?;
+        let mut changes = vec![];
+        while let Some(change) = cursor.next().await {
+            changes.push(change?);
+        }
+        Ok(changes)
+    }
+
+    // Every chat and every group the user is in
+    pub async fn get_conversation_ids(
+        &self,
+        user: ObjectId,
+    ) -> Result<(Vec<ObjectId>, Vec<ObjectId>), MyError> {
+        let chats = self.chats.distinct("_id", doc! {"users":user}).await?;
+        let groups = self.groups.distinct("_id", doc! {"members":user}).await?;
+        Ok((
+            chats.iter().filter_map(Bson::as_object_id).collect(),
+            groups.iter().filter_map(Bson::as_object_id).collect(),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mongodb::error::WriteError;
+
+    fn write_error(code: i32) -> Error {
+        let w: WriteError = bson::from_document(doc! {"code":code,"errmsg":"test"}).unwrap();
+        Error::from(ErrorKind::Write(WriteFailure::WriteError(w)))
+    }
+
+    #[test]
+    fn only_sends_with_a_client_msg_id_are_retries() {
+        let from = ObjectId::new();
+        assert_eq!(retry_filter(Some(from), &None), None);
+        let filter = retry_filter(Some(from), &Some(String::from("m1")));
+        assert_eq!(filter, Some(doc! {"from_id":from,"client_msg_id":"m1"}));
+    }
+
+    #[test]
+    fn a_second_insert_of_the_same_send_is_a_duplicate() {
+        assert!(is_duplicate_key(&write_error(11000)));
+        assert!(!is_duplicate_key(&write_error(121)));
+    }
+
+    #[test]
+    fn watermark_filters_use_seq_when_there_is_one() {
+        let message_id = ObjectId::new();
+        let mark = Watermark {
+            message_id,
+            seq: Some(7),
+        };
+        assert_eq!(
+            after_watermark(&mark),
+            doc! {"seq":{"$gt":7_i64},"thread_id":{"$exists":false}}
+        );
+        assert_eq!(
+            up_to_watermark(&mark),
+            doc! {"seq":{"$lte":7_i64},"thread_id":{"$exists":false}}
+        );
+        let legacy = Watermark {
+            message_id,
+            seq: None,
+        };
+        assert_eq!(after_watermark(&legacy), doc! {"_id":{"$gt":message_id}});
+        assert_eq!(up_to_watermark(&legacy), doc! {"_id":{"$lte":message_id}});
+    }
+
+    #[test]
+    fn raising_to_a_message_without_seq_leaves_the_seq_alone() {
+        let message_id = ObjectId::new();
+        let with_seq = raise_watermark(
+            "read_up_to",
+            &Watermark {
+                message_id,
+                seq: Some(3),
+            },
+        );
+        let keys: Vec<&String> = with_seq.keys().collect();
+        assert_eq!(keys, ["read_up_to", "read_up_to_seq"]);
+        let without = raise_watermark(
+            "read_up_to",
+            &Watermark {
+                message_id,
+                seq: None,
+            },
+        );
+        let keys: Vec<&String> = without.keys().collect();
+        assert_eq!(keys, ["read_up_to"]);
+    }
+
+    #[test]
+    fn legacy_creator_owns_the_group() {
+        let (creator, admin) = (ObjectId::new(), ObjectId::new());
+        let (roles, owner) = legacy_roles(&[admin, creator], Some(creator));
+        assert_eq!(owner, Some(creator));
+        assert_eq!(roles, doc! {admin.to_hex():"admin",creator.to_hex():"owner"});
+    }
+
+    #[test]
+    fn legacy_only_admin_owns_the_group() {
+        let admin = ObjectId::new();
+        let (roles, owner) = legacy_roles(&[admin], None);
+        assert_eq!(owner, Some(admin));
+        assert_eq!(roles, doc! {admin.to_hex():"owner"});
+    }
+
+    #[test]
+    fn legacy_admins_without_creator_get_no_owner() {
+        let (a, b) = (ObjectId::new(), ObjectId::new());
+        let (roles, owner) = legacy_roles(&[a, b], None);
+        assert_eq!(owner, None);
+        assert_eq!(roles, doc! {a.to_hex():"admin",b.to_hex():"admin"});
+        assert_eq!(legacy_roles(&[], None), (Document::new(), None));
+    }
 }
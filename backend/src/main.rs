@@ -3,6 +3,7 @@ use std::{env};
 
 use crate::server::Server;
 
+mod authz;
 mod db;
 mod middleware;
 mod models;
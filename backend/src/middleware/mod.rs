@@ -8,9 +8,13 @@ use axum::{
     response::IntoResponse,
     Json,
 };
+use log::error;
 use serde_json::json;
 
-use crate::{db::Db,utils::validate_jwt}; //, models::Claims};
+use crate::{
+    db::Db,
+    utils::{find_cookie, validate_jwt, ACCESS_COOKIE},
+}; //, models::Claims};
 
 
 pub async fn auth_middleware(
@@ -18,39 +22,10 @@ pub async fn auth_middleware(
     req: Request<Body>,
     next: Next,
 ) -> Result<Response<Body>, impl IntoResponse> {
-    let header = req.headers().get("Cookie");
-    match header {
-        Some(h) => {
-            // println!("{:?}",h);
-            let auth = h.to_str().map_err(|_| {
-                (
-                    StatusCode::BAD_REQUEST,
-                    Json(json!({
-                        "err":"please login"
-                    })),
-                )
-            })?;
-            let auth_token: &str;
-            if let Some((name, token)) = auth.split_once("=") {
-                if name == "jwt" {
-                    auth_token = token;
-                } else {
-                    return Err((
-                        StatusCode::NOT_ACCEPTABLE,
-                        Json(json!({
-                            "err":"invalid token string"
-                        })),
-                    ));
-                }
-            } else {
-                return Err((
-                    StatusCode::NOT_ACCEPTABLE,
-                    Json(json!({
-                        "err":"invalid token string 2"
-                    })),
-                ));
-            }
-            let result = validate_jwt(auth_token);
+    let token = find_cookie(req.headers(), ACCESS_COOKIE);
+    match token {
+        Some(auth_token) => {
+            let result = validate_jwt(&auth_token, &db).await;
             match result {
                 Ok(claims) => {
                     // println!("{}", id);
@@ -61,12 +36,12 @@ pub async fn auth_middleware(
                             Ok(next.run(req).await)
                         }
                         None => {
-                            return Err((
+                            Err((
                                 StatusCode::OK,
                                 Json(json!({
                                     "success":true
                                 })),
-                            ));
+                            ))
                         }
                     }
                     // match user {
@@ -89,7 +64,7 @@ pub async fn auth_middleware(
                     // }
                 }
                 Err(e) => {
-                    println!("{}", e.to_string());
+                    error!("{}", e);
                     Err((
                         StatusCode::UNAUTHORIZED,
                         Json(json!({
@@ -1,9 +1,13 @@
-use std::collections::HashSet;
+use std::{
+    collections::{HashMap, HashSet},
+    time::Duration,
+};
 
 use argon2::{
     password_hash::{rand_core::OsRng, PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
     Argon2,
 };
+use axum::http::StatusCode;
 use log::error;
 use mongodb::bson::{oid::ObjectId, DateTime};
 use serde::{Deserialize, Serialize};
@@ -26,6 +30,9 @@ pub struct User {
     pub created_at: Option<DateTime>,
     pub updated_at: Option<DateTime>,
     pub last_login: Option<DateTime>,
+    // when the last socket closed, only handed out to friends through presence
+    #[serde(default, skip_serializing)]
+    pub last_seen: Option<DateTime>,
 }
 
 impl User {
@@ -119,7 +126,17 @@ impl Chat {
             from_id: None,
             content: String::new(),
             chat_id: None,
+            client_msg_id: None,
+            seq: None,
+            reply_to: None,
+            edits: vec![],
+            hidden_for: vec![],
+            reactions: vec![],
+            reaction_counts: vec![],
+            deleted_by: None,
             created_at: Some(DateTime::now()),
+            edited_at: None,
+            deleted_at: None,
         };
         Chat {
             id: None,
@@ -129,6 +146,19 @@ impl Chat {
         }
     }
 
+    pub fn users(&self) -> &[ObjectId] {
+        &self.users
+    }
+
+    pub fn has_user(&self, user: ObjectId) -> bool {
+        self.users.contains(&user)
+    }
+
+    // The participant of a direct chat who is not `user`
+    pub fn other_user(&self, user: ObjectId) -> Option<ObjectId> {
+        self.users.iter().find(|u| **u != user).copied()
+    }
+
     pub async fn convert(&self, id: impl IntoObjectId, db: &Db) -> Option<Conversation> {
         let last_message = match self.last_message_update {
             Some(msg) => {
@@ -139,9 +169,17 @@ impl Chat {
             None => None
         };
         log::debug!("{:?}",self.last_message_update);
+        let viewer = id.to_string().into_object_id();
+        let unread = match self.id {
+            Some(chat_id) => db.unread_in_chat(viewer, chat_id).await.unwrap_or_else(|e| {
+                error!("{}", e);
+                0
+            }),
+            None => 0,
+        };
         match self.users.as_slice() {
             [user1, user2] => {
-                if user1.to_hex() == id.to_string() {
+                if *user1 == viewer {
                     let user = db.find_user_with_id(user2.into_object_id()).await.unwrap();
                     Some(Conversation {
                         id: self.id,
@@ -152,6 +190,7 @@ impl Chat {
                             username: user.username,
                         },
                         last_updated_message: last_message,
+                        unread,
                     })
                 } else {
                     let user = db.find_user_with_id(user1.into_object_id()).await.unwrap();
@@ -164,6 +203,7 @@ impl Chat {
                             username: user.username,
                         },
                         last_updated_message: last_message,
+                        unread,
                     })
                 }
             }
@@ -181,6 +221,8 @@ pub struct Conversation {
     sender: ObjectId,
     receiver: TempUser,
     last_updated_message: Option<DirectMessage>,
+    // messages from the other user after the viewer's read watermark
+    unread: u64,
 }
 #[derive(Deserialize, Serialize, Debug, Clone)]
 struct TempUser {
@@ -189,25 +231,249 @@ struct TempUser {
     username: String,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Group {
     #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
     pub id: Option<ObjectId>,
-    admins: HashSet<ObjectId>,
+    #[serde(default)]
+    pub name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    // url or storage key of the picture, the backend does not host files
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub avatar: Option<String>,
+    #[serde(default)]
+    pub settings: GroupSettings,
+    // Keyed by user id hex, members without an entry are plain members
+    #[serde(default)]
+    roles: HashMap<String, GroupRole>,
     pub members: HashSet<ObjectId>,
+    // cannot be added back or join again until unbanned
+    #[serde(default)]
+    pub banned: HashSet<ObjectId>,
+    // user id hex to when their mute ends, entries in the past mean nothing
+    #[serde(default)]
+    muted: HashMap<String, DateTime>,
+    #[serde(
+        rename = "last_updated_message",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub last_message_update: Option<ObjectId>,
     //DateTime fields
     pub created_at: DateTime,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub updated_at: Option<DateTime>,
 }
 
 impl Group {
-    pub fn new(admins: HashSet<ObjectId>, members: HashSet<ObjectId>) -> Group {
+    pub fn new(owner: ObjectId, members: HashSet<ObjectId>, details: NewGroup) -> Group {
+        let mut roles = HashMap::new();
+        roles.insert(owner.to_hex(), GroupRole::Owner);
         Group {
             id: None,
-            admins,
+            name: details.name,
+            description: details.description,
+            avatar: details.avatar,
+            settings: details.settings,
+            roles,
             members,
+            banned: HashSet::new(),
+            muted: HashMap::new(),
+            last_message_update: None,
             created_at: DateTime::now(),
+            updated_at: None,
+        }
+    }
+
+    // None when `user` is not in the group at all
+    pub fn role_of(&self, user: ObjectId) -> Option<GroupRole> {
+        if !self.members.contains(&user) {
+            return None;
         }
+        Some(
+            self.roles
+                .get(&user.to_hex())
+                .copied()
+                .unwrap_or(GroupRole::Member),
+        )
     }
+
+    pub fn can(&self, user: ObjectId, permission: GroupPermission) -> bool {
+        let role = match self.role_of(user) {
+            Some(r) => r,
+            None => return false,
+        };
+        // posting and adding are open to everyone unless the settings narrow them to admins
+        let policy = match permission {
+            GroupPermission::Post => Some(self.settings.who_can_post),
+            GroupPermission::AddMembers => Some(self.settings.who_can_add_members),
+            _ => None,
+        };
+        match policy {
+            Some(GroupPolicy::Admins) => role >= GroupRole::Admin,
+            _ => role.allows(permission),
+        }
+    }
+
+    pub fn members_who_can(&self, permission: GroupPermission) -> Vec<ObjectId> {
+        self.members
+            .iter()
+            .filter(|m| self.can(**m, permission))
+            .copied()
+            .collect()
+    }
+
+    pub fn muted_until(&self, user: ObjectId) -> Option<DateTime> {
+        self.muted
+            .get(&user.to_hex())
+            .copied()
+            .filter(|until| *until > DateTime::now())
+    }
+
+    // Acting on another member (removing, changing their role) needs a strictly higher role
+    pub fn outranks(&self, actor: ObjectId, target: ObjectId) -> bool {
+        match (self.role_of(actor), self.role_of(target)) {
+            (Some(a), Some(t)) => a > t,
+            (Some(_), None) => true,
+            _ => false,
+        }
+    }
+
+    pub async fn convert(&self, viewer: ObjectId, db: &Db) -> GroupConversation {
+        let last_message = match self.last_message_update {
+            Some(msg) => db.find_group_message(msg).await,
+            None => None,
+        };
+        let unread = match self.id {
+            Some(group_id) => db.unread_in_group(viewer, group_id).await.unwrap_or_else(|e| {
+                error!("{}", e);
+                0
+            }),
+            None => 0,
+        };
+        GroupConversation {
+            id: self.id,
+            name: self.name.clone(),
+            description: self.description.clone(),
+            avatar: self.avatar.clone(),
+            settings: self.settings.clone(),
+            roles: self.roles.clone(),
+            members: self.members.clone(),
+            last_updated_message: last_message,
+            unread,
+            created_at: self.created_at,
+        }
+    }
+}
+
+// Declared from least to most privileged so roles compare by rank
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "lowercase")]
+pub enum GroupRole {
+    Member,
+    Moderator,
+    Admin,
+    Owner,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum GroupPermission {
+    Post,
+    AddMembers,
+    RemoveMembers,
+    DeleteMessages,
+    UpdateDetails,
+    ManageModerators,
+    MuteMembers,
+    BanMembers,
+    ManageInvites,
+    HandleJoinRequests,
+    ManageAdmins,
+    TransferOwnership,
+}
+
+impl GroupRole {
+    pub fn allows(self, permission: GroupPermission) -> bool {
+        match permission {
+            GroupPermission::Post | GroupPermission::AddMembers => true,
+            GroupPermission::DeleteMessages | GroupPermission::MuteMembers => {
+                self >= GroupRole::Moderator
+            }
+            GroupPermission::RemoveMembers
+            | GroupPermission::BanMembers
+            | GroupPermission::UpdateDetails
+            | GroupPermission::ManageModerators
+            | GroupPermission::ManageInvites
+            | GroupPermission::HandleJoinRequests => self >= GroupRole::Admin,
+            GroupPermission::ManageAdmins | GroupPermission::TransferOwnership => {
+                self == GroupRole::Owner
+            }
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum GroupPolicy {
+    Everyone,
+    Admins,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct GroupSettings {
+    pub who_can_post: GroupPolicy,
+    pub who_can_add_members: GroupPolicy,
+    // when set, joining needs an admin to approve
+    pub join_approval: bool,
+}
+
+impl Default for GroupSettings {
+    fn default() -> Self {
+        GroupSettings {
+            who_can_post: GroupPolicy::Everyone,
+            who_can_add_members: GroupPolicy::Admins,
+            join_approval: false,
+        }
+    }
+}
+
+pub const MAX_GROUP_NAME_LEN: usize = 100;
+pub const MAX_GROUP_DESCRIPTION_LEN: usize = 500;
+
+pub fn validate_group_details(name: Option<&str>, description: Option<&str>) -> Result<(), String> {
+    if let Some(name) = name {
+        if name.trim().is_empty() || name.chars().count() > MAX_GROUP_NAME_LEN {
+            return Err(format!(
+                "group name must be between 1 and {} characters",
+                MAX_GROUP_NAME_LEN
+            ));
+        }
+    }
+    if let Some(description) = description {
+        if description.chars().count() > MAX_GROUP_DESCRIPTION_LEN {
+            return Err(format!(
+                "group description can be at most {} characters",
+                MAX_GROUP_DESCRIPTION_LEN
+            ));
+        }
+    }
+    Ok(())
+}
+
+// Group counterpart of Conversation for the group list
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct GroupConversation {
+    id: Option<ObjectId>,
+    name: String,
+    description: Option<String>,
+    avatar: Option<String>,
+    settings: GroupSettings,
+    roles: HashMap<String, GroupRole>,
+    members: HashSet<ObjectId>,
+    last_updated_message: Option<GroupMessage>,
+    unread: u64,
+    created_at: DateTime,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -218,8 +484,37 @@ pub struct DirectMessage {
     pub from_id: Option<ObjectId>,
     pub to_id: Option<ObjectId>,
     pub content: String,
+    // Generated by the client so a retried send can be recognised
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub client_msg_id: Option<String>,
+    // position in the chat, 1, 2, 3, .. handed out by the server. A send that fails after getting
+    // its number leaves a gap, so a missing number means refetch that range, not wait for it.
+    // Messages stored before seqs existed have none.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub seq: Option<i64>,
+    // the message this one quotes, always from the same chat
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reply_to: Option<ObjectId>,
+    // what the message said before each edit, oldest first
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub edits: Vec<MessageRevision>,
+    // users who deleted it for themselves only
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub hidden_for: Vec<ObjectId>,
+    // one entry per user and emoji, clients get them as `reaction_counts` instead
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub reactions: Vec<Reaction>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub reaction_counts: Vec<ReactionCount>,
+    // set when deleted for everyone, the content is gone and only this tombstone is left
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub deleted_by: Option<ObjectId>,
     //DateTime fields
     pub created_at: Option<DateTime>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub edited_at: Option<DateTime>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub deleted_at: Option<DateTime>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -251,13 +546,601 @@ impl Requests {
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct GroupMessage {
     #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
-    id: Option<ObjectId>,
+    pub id: Option<ObjectId>,
     pub group_id: Option<ObjectId>,
     pub from_id: Option<ObjectId>,
     pub content: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub client_msg_id: Option<String>,
+    // position in the group timeline, or in the thread for replies
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub seq: Option<i64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reply_to: Option<ObjectId>,
+    // set on replies in a thread, the root of a thread never has one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub thread_id: Option<ObjectId>,
+    // only on thread roots
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub reply_count: u32,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub edits: Vec<MessageRevision>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub hidden_for: Vec<ObjectId>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub reactions: Vec<Reaction>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub reaction_counts: Vec<ReactionCount>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub deleted_by: Option<ObjectId>,
     //DateTime fields
     #[serde(skip_serializing_if = "Option::is_none")]
     pub created_at: Option<DateTime>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub edited_at: Option<DateTime>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub deleted_at: Option<DateTime>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_reply_at: Option<DateTime>,
+}
+
+fn is_zero(n: &u32) -> bool {
+    *n == 0
+}
+
+// Seqs, edits, reactions, deletion and thread counters are only ever set by the server, never taken from what a client sent
+impl DirectMessage {
+    pub fn clear_server_fields(&mut self) {
+        self.seq = None;
+        self.edits.clear();
+        self.hidden_for.clear();
+        self.reactions.clear();
+        self.reaction_counts.clear();
+        self.deleted_by = None;
+        self.edited_at = None;
+        self.deleted_at = None;
+    }
+}
+
+impl GroupMessage {
+    pub fn clear_server_fields(&mut self) {
+        self.seq = None;
+        self.edits.clear();
+        self.hidden_for.clear();
+        self.reactions.clear();
+        self.reaction_counts.clear();
+        self.deleted_by = None;
+        self.edited_at = None;
+        self.deleted_at = None;
+        self.reply_count = 0;
+        self.last_reply_at = None;
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct MessageRevision {
+    pub content: String,
+    // when this version was written
+    pub created_at: Option<DateTime>,
+}
+
+// distinct reactions one user can leave on one message
+pub const MAX_REACTIONS_PER_USER: i32 = 8;
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct Reaction {
+    pub emoji: String,
+    pub user_id: ObjectId,
+}
+
+impl Reaction {
+    /// True when `emoji` is exactly one emoji, skin tone and ZWJ sequences included.
+    pub fn is_emoji(emoji: &str) -> bool {
+        emojis::get(emoji).is_some()
+    }
+}
+
+// How many users reacted with one emoji, in the order the emoji was first used
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ReactionCount {
+    pub emoji: String,
+    pub count: usize,
+    pub users: Vec<ObjectId>,
+}
+
+pub fn count_reactions(reactions: &[Reaction]) -> Vec<ReactionCount> {
+    let mut counts: Vec<ReactionCount> = Vec::new();
+    for r in reactions {
+        match counts.iter_mut().find(|c| c.emoji == r.emoji) {
+            Some(c) => {
+                c.count += 1;
+                c.users.push(r.user_id);
+            }
+            None => counts.push(ReactionCount {
+                emoji: r.emoji.clone(),
+                count: 1,
+                users: vec![r.user_id],
+            }),
+        }
+    }
+    counts
+}
+
+// Points at one stored message, `type` is the same as on the ChatMessage it was sent as
+#[derive(Debug, Serialize, Deserialize, Clone, Copy)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum MessageRef {
+    Direct {
+        chat_id: ObjectId,
+        message_id: ObjectId,
+    },
+    Group {
+        group_id: ObjectId,
+        message_id: ObjectId,
+    },
+}
+
+impl MessageRef {
+    pub fn message_id(&self) -> ObjectId {
+        match self {
+            MessageRef::Direct { message_id, .. } | MessageRef::Group { message_id, .. } => {
+                *message_id
+            }
+        }
+    }
+
+    pub fn conversation(&self) -> ConversationRef {
+        match *self {
+            MessageRef::Direct { chat_id, .. } => ConversationRef::Direct { chat_id },
+            MessageRef::Group { group_id, .. } => ConversationRef::Group { group_id },
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct MessageEdit {
+    #[serde(flatten)]
+    pub target: MessageRef,
+    pub content: String,
+}
+
+// Online has a socket that sent something lately, away only has idle sockets
+#[derive(Debug, Serialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Presence {
+    Online,
+    Away,
+    Offline,
+}
+
+#[derive(Debug, Serialize, Clone)]
+pub struct PresenceStatus {
+    pub user_id: ObjectId,
+    pub presence: Presence,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_seen: Option<DateTime>,
+}
+
+pub const MAX_PRESENCE_QUERY: usize = 200;
+
+#[derive(Debug, Deserialize)]
+pub struct PresenceQuery {
+    pub user_ids: Vec<ObjectId>,
+}
+
+// One chat or group, `type` is the same as on MessageRef
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum ConversationRef {
+    Direct { chat_id: ObjectId },
+    Group { group_id: ObjectId },
+}
+
+impl ConversationRef {
+    pub fn message(&self, message_id: ObjectId) -> MessageRef {
+        match *self {
+            ConversationRef::Direct { chat_id } => MessageRef::Direct { chat_id, message_id },
+            ConversationRef::Group { group_id } => MessageRef::Group { group_id, message_id },
+        }
+    }
+}
+
+// Change log
+
+// Everything that happened to messages, in one global order, so a client coming back can
+// catch up from the last `seq` it saw instead of fetching whole histories again
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Change {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<ObjectId>,
+    pub seq: i64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub chat_id: Option<ObjectId>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub group_id: Option<ObjectId>,
+    // set when only one user is meant to see it, like a delete for me
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub only_for: Option<ObjectId>,
+    pub event: ChangeEvent,
+    //DateTime fields
+    pub created_at: DateTime,
+}
+
+impl Change {
+    pub fn conversation(&self) -> Option<ConversationRef> {
+        match (self.chat_id, self.group_id) {
+            (Some(chat_id), _) => Some(ConversationRef::Direct { chat_id }),
+            (None, Some(group_id)) => Some(ConversationRef::Group { group_id }),
+            (None, None) => None,
+        }
+    }
+
+    pub fn new(conversation: ConversationRef, only_for: Option<ObjectId>, event: ChangeEvent) -> Change {
+        let (chat_id, group_id) = match conversation {
+            ConversationRef::Direct { chat_id } => (Some(chat_id), None),
+            ConversationRef::Group { group_id } => (None, Some(group_id)),
+        };
+        Change {
+            id: None,
+            seq: 0,
+            chat_id,
+            group_id,
+            only_for,
+            event,
+            created_at: DateTime::now(),
+        }
+    }
+}
+
+// Same payloads as the live frames for them
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum ChangeEvent {
+    Message { message: ChatMessage },
+    MessageEdited { message: ChatMessage },
+    MessageDeleted { message: ChatMessage },
+    MessageHidden { message_id: ObjectId },
+    ReactionsUpdated { message: ChatMessage },
+}
+
+// How long the change log goes back, a client away for longer has to refetch
+pub const CHANGE_LOG_TTL_SECS: u64 = 30 * 24 * 3600;
+// How long a seq can be handed out without its change showing up before it counts as lost
+pub const CHANGE_SETTLE_SECS: u64 = 10;
+pub const MAX_SYNC_CHANGES: i64 = 500;
+
+#[derive(Debug, Deserialize)]
+pub struct ConversationCursor {
+    #[serde(flatten)]
+    pub target: ConversationRef,
+    // the newest message the client already has there, by `_id` or by `seq`
+    #[serde(default)]
+    pub after: Option<ObjectId>,
+    #[serde(default)]
+    pub after_seq: Option<i64>,
+}
+
+// Either everything after a global `since`, or each listed conversation after its own cursor
+#[derive(Debug, Deserialize)]
+pub struct SyncRequest {
+    pub since: Option<i64>,
+    #[serde(default)]
+    pub conversations: Vec<ConversationCursor>,
+}
+
+// A thread in a group, named by the `_id` of its root message
+#[derive(Debug, Deserialize, Clone, Copy)]
+pub struct ThreadRef {
+    pub group_id: ObjectId,
+    pub thread_id: ObjectId,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ReactionChange {
+    #[serde(flatten)]
+    pub target: MessageRef,
+    pub emoji: String,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum DeleteScope {
+    Me,
+    Everyone,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct MessageDelete {
+    #[serde(flatten)]
+    pub target: MessageRef,
+    pub scope: DeleteScope,
+}
+
+// How far one user got in one chat or group. Both watermarks cover every message up to and
+// including the one they name. The `_seq` fields hold that message's seq and decide the order;
+// markers written before seqs existed only have the ids.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ReadMarker {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<ObjectId>,
+    pub user_id: ObjectId,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub chat_id: Option<ObjectId>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub group_id: Option<ObjectId>,
+    // the latest message one of the user's sockets received
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub delivered_up_to: Option<ObjectId>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub delivered_up_to_seq: Option<i64>,
+    // the latest message the user's client says was shown
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub read_up_to: Option<ObjectId>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub read_up_to_seq: Option<i64>,
+    //DateTime fields
+    pub updated_at: DateTime,
+}
+
+impl ReadMarker {
+    pub fn delivered(&self) -> Option<Watermark> {
+        self.delivered_up_to.map(|message_id| Watermark {
+            message_id,
+            seq: self.delivered_up_to_seq,
+        })
+    }
+
+    pub fn read(&self) -> Option<Watermark> {
+        self.read_up_to.map(|message_id| Watermark {
+            message_id,
+            seq: self.read_up_to_seq,
+        })
+    }
+}
+
+// One watermark: the message it stops at and that message's seq in the chat or group timeline.
+// Thread replies and messages stored before seqs existed have no seq.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Watermark {
+    pub message_id: ObjectId,
+    pub seq: Option<i64>,
+}
+
+impl Watermark {
+    // Whether moving to `to` goes forward. Once a marker has a seq only a later seq moves it;
+    // a marker from before seqs existed goes by `_id`.
+    pub fn is_before(&self, to: &Watermark) -> bool {
+        match (self.seq, to.seq) {
+            (Some(from), Some(to)) => from < to,
+            (Some(_), None) => false,
+            (None, _) => self.message_id < to.message_id,
+        }
+    }
+}
+
+// A watermark raise: where it was, None for the first one, and the message it was asked to move to
+#[derive(Debug, Clone, Copy)]
+pub struct WatermarkMove {
+    pub from: Option<Watermark>,
+    pub to: Watermark,
+}
+
+impl WatermarkMove {
+    pub fn advanced(&self) -> bool {
+        self.from.is_none_or(|from| from.is_before(&self.to))
+    }
+}
+
+// Sent to the senders: `users` got or read everything up to `message_id`, which is at `seq`
+// in the timeline when it has one
+#[derive(Debug, Serialize, Clone)]
+pub struct Receipt {
+    #[serde(flatten)]
+    pub target: MessageRef,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub seq: Option<i64>,
+    pub users: Vec<ObjectId>,
+    pub at: DateTime,
+}
+
+// Written by the server into a chat or group timeline for things that are not messages,
+// so every client can show "Alice added Bob" the same way. `from_id` is who caused it.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct SystemMessage {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<ObjectId>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub chat_id: Option<ObjectId>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub group_id: Option<ObjectId>,
+    pub from_id: Option<ObjectId>,
+    // counted with the messages around it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub seq: Option<i64>,
+    pub event: SystemEvent,
+    //DateTime fields
+    pub created_at: Option<DateTime>,
+}
+
+impl SystemMessage {
+    pub fn chat(chat_id: ObjectId, actor: ObjectId, event: SystemEvent) -> SystemMessage {
+        SystemMessage {
+            id: None,
+            chat_id: Some(chat_id),
+            group_id: None,
+            from_id: Some(actor),
+            seq: None,
+            event,
+            created_at: Some(DateTime::now()),
+        }
+    }
+
+    pub fn group(group_id: ObjectId, actor: ObjectId, event: SystemEvent) -> SystemMessage {
+        SystemMessage {
+            id: None,
+            chat_id: None,
+            group_id: Some(group_id),
+            from_id: Some(actor),
+            seq: None,
+            event,
+            created_at: Some(DateTime::now()),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum SystemEvent {
+    ChatCreated,
+    GroupCreated { name: String },
+    GroupRenamed { name: String },
+    GroupDescriptionChanged { description: String },
+    GroupAvatarChanged { avatar: String },
+    GroupSettingsChanged { settings: GroupSettings },
+    MembersAdded { users: Vec<ObjectId> },
+    MemberJoined { user: ObjectId },
+    MemberLeft { user: ObjectId },
+    MemberKicked { user: ObjectId },
+    MemberBanned { user: ObjectId },
+    MemberUnbanned { user: ObjectId },
+    MemberMuted { user: ObjectId, until: DateTime },
+    MemberUnmuted { user: ObjectId },
+    RoleChanged { user: ObjectId, role: GroupRole },
+    OwnershipTransferred { user: ObjectId },
+}
+
+// How a stored timeline document is read back, system messages are the ones with an `event`
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+pub enum TimelineEntry<M> {
+    System(SystemMessage),
+    Message(M),
+}
+
+impl From<TimelineEntry<DirectMessage>> for ChatMessage {
+    fn from(entry: TimelineEntry<DirectMessage>) -> Self {
+        match entry {
+            TimelineEntry::System(m) => ChatMessage::System(m),
+            TimelineEntry::Message(m) => ChatMessage::Direct(m),
+        }
+    }
+}
+
+impl From<TimelineEntry<GroupMessage>> for ChatMessage {
+    fn from(entry: TimelineEntry<GroupMessage>) -> Self {
+        match entry {
+            TimelineEntry::System(m) => ChatMessage::System(m),
+            TimelineEntry::Message(m) => ChatMessage::Group(m),
+        }
+    }
+}
+
+// One login of a user. Every refresh token issued for this login belongs to the
+// same family and only the latest one (refresh_jti) is allowed to be used.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Session {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<ObjectId>,
+    pub user_id: ObjectId,
+    pub refresh_jti: String,
+    // the token `refresh_jti` replaced, still accepted for a moment after `rotated_at`
+    // so two tabs refreshing at once do not look like a stolen token
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub previous_jti: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rotated_at: Option<DateTime>,
+    pub revoked: bool,
+    //Device metadata
+    #[serde(default)]
+    pub user_agent: Option<String>,
+    #[serde(default)]
+    pub ip: Option<String>,
+    //DateTime fields
+    pub created_at: DateTime,
+    #[serde(default)]
+    pub last_seen_at: Option<DateTime>,
+    pub expires_at: DateTime,
+}
+
+impl Session {
+    pub fn new(
+        id: ObjectId,
+        user_id: ObjectId,
+        refresh_jti: String,
+        device: DeviceInfo,
+        expires_at: DateTime,
+    ) -> Session {
+        Session {
+            id: Some(id),
+            user_id,
+            refresh_jti,
+            previous_jti: None,
+            rotated_at: None,
+            revoked: false,
+            user_agent: device.user_agent,
+            ip: device.ip,
+            created_at: DateTime::now(),
+            last_seen_at: Some(DateTime::now()),
+            expires_at,
+        }
+    }
+
+    pub fn info(&self, current_session: &str) -> SessionInfo {
+        SessionInfo {
+            id: self.id,
+            user_agent: self.user_agent.clone(),
+            ip: self.ip.clone(),
+            created_at: self.created_at,
+            last_seen_at: self.last_seen_at,
+            current: self.id.map(|id| id.to_hex()).as_deref() == Some(current_session),
+        }
+    }
+
+    /// What presenting the refresh token `jti` at `now` means for this session.
+    pub fn check_refresh(&self, jti: &str, now: DateTime, grace: Duration) -> RefreshCheck {
+        if self.revoked || self.expires_at <= now {
+            return RefreshCheck::Reuse;
+        }
+        if self.refresh_jti == jti {
+            return RefreshCheck::Rotate;
+        }
+        let recent = self.rotated_at.is_some_and(|at| {
+            at.timestamp_millis() + grace.as_millis() as i64 > now.timestamp_millis()
+        });
+        if recent && self.previous_jti.as_deref() == Some(jti) {
+            RefreshCheck::Replay(self.refresh_jti.clone())
+        } else {
+            RefreshCheck::Reuse
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum RefreshCheck {
+    // the current token, it gets swapped for a new one
+    Rotate,
+    // the token just rotated away, still inside the grace window. Gets the current one back.
+    Replay(String),
+    // an older token came back or the session is dead
+    Reuse,
+}
+
+// What the user gets to see about a session, never the refresh token id
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct SessionInfo {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<ObjectId>,
+    pub user_agent: Option<String>,
+    pub ip: Option<String>,
+    pub created_at: DateTime,
+    pub last_seen_at: Option<DateTime>,
+    pub current: bool,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct DeviceInfo {
+    pub user_agent: Option<String>,
+    pub ip: Option<String>,
 }
 
 //Utility Models
@@ -271,6 +1154,15 @@ pub struct LoginUser {
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Claims {
     pub sub: String,
+    pub sid: String,
+    pub exp: usize,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RefreshClaims {
+    pub sub: String,
+    pub sid: String,
+    pub jti: String,
     pub exp: usize,
 }
 
@@ -299,9 +1191,177 @@ pub enum FriendRequest {
     Reject { from_id: String },
 }
 
+// Body of /create/group
 #[derive(Debug, Serialize, Deserialize)]
-pub struct Members {
+pub struct NewGroup {
     pub members: HashSet<String>,
+    pub name: String,
+    #[serde(default)]
+    pub description: Option<String>,
+    #[serde(default)]
+    pub avatar: Option<String>,
+    #[serde(default)]
+    pub settings: GroupSettings,
+}
+
+// Body of /group/update, only the fields that are present get changed
+#[derive(Debug, Serialize, Deserialize)]
+pub struct GroupUpdate {
+    pub group_id: ObjectId,
+    pub name: Option<String>,
+    pub description: Option<String>,
+    pub avatar: Option<String>,
+    pub settings: Option<GroupSettings>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct GroupRoleChange {
+    pub group_id: ObjectId,
+    pub user_id: ObjectId,
+    pub role: GroupRole,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct OwnershipTransfer {
+    pub group_id: ObjectId,
+    pub user_id: ObjectId,
+}
+
+pub const MAX_MUTE_SECS: u64 = 30 * 24 * 3600;
+
+// Body of the kick, ban, unban and unmute routes
+#[derive(Debug, Serialize, Deserialize)]
+pub struct MemberAction {
+    pub group_id: ObjectId,
+    pub user_id: ObjectId,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct MuteMember {
+    pub group_id: ObjectId,
+    pub user_id: ObjectId,
+    // seconds from now
+    pub duration: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct LeaveGroup {
+    pub group_id: ObjectId,
+}
+
+// A shareable token that lets anyone holding it join `group_id`
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct GroupInvite {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<ObjectId>,
+    pub group_id: ObjectId,
+    pub token: String,
+    pub created_by: ObjectId,
+    // unlimited when not set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_uses: Option<u32>,
+    #[serde(default)]
+    pub uses: u32,
+    #[serde(default)]
+    pub revoked: bool,
+    //DateTime fields
+    pub created_at: DateTime,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<DateTime>,
+}
+
+impl GroupInvite {
+    pub fn new(token: String, created_by: ObjectId, details: NewInvite) -> GroupInvite {
+        let now = DateTime::now();
+        let expires_at = details
+            .expires_in
+            .map(|secs| DateTime::from_millis(now.timestamp_millis() + secs as i64 * 1000));
+        GroupInvite {
+            id: None,
+            group_id: details.group_id,
+            token,
+            created_by,
+            max_uses: details.max_uses,
+            uses: 0,
+            revoked: false,
+            created_at: now,
+            expires_at,
+        }
+    }
+
+    // Whether it could still let someone in at `now`, without using it up
+    pub fn usable(&self, now: DateTime) -> bool {
+        !self.revoked
+            && self.expires_at.is_none_or(|t| t > now)
+            && self.max_uses.is_none_or(|max| self.uses < max)
+    }
+}
+
+pub const MAX_INVITE_EXPIRY_SECS: u64 = 30 * 24 * 3600;
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct NewInvite {
+    pub group_id: ObjectId,
+    // seconds from now
+    pub expires_in: Option<u64>,
+    pub max_uses: Option<u32>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct InviteRevoke {
+    pub invite_id: ObjectId,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct InviteJoin {
+    pub token: String,
+}
+
+// Same lifecycle as a friend Requests but for joining a group that needs approval
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct GroupJoinRequest {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<ObjectId>,
+    pub group_id: ObjectId,
+    pub from_id: ObjectId,
+    pub status: String,
+    // the invite the request came through, it is only used up once the request is approved
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub invite_id: Option<ObjectId>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub handled_by: Option<ObjectId>,
+    //DateTime fields
+    pub created_at: DateTime,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub handled_at: Option<DateTime>,
+}
+
+impl GroupJoinRequest {
+    pub fn new(group_id: ObjectId, from_id: ObjectId, invite_id: Option<ObjectId>) -> GroupJoinRequest {
+        GroupJoinRequest {
+            id: None,
+            group_id,
+            from_id,
+            status: String::from("pending"),
+            invite_id,
+            handled_by: None,
+            created_at: DateTime::now(),
+            handled_at: None,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct NewJoinRequest {
+    pub group_id: ObjectId,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "action")]
+#[serde(rename_all = "lowercase")]
+pub enum JoinRequestAction {
+    Approve { request_id: ObjectId },
+    Reject { request_id: ObjectId },
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -315,7 +1375,382 @@ pub struct ChatRequest {
 pub enum ChatMessage {
     Direct(DirectMessage),
     Group(GroupMessage),
+    System(SystemMessage),
+}
+
+impl ChatMessage {
+    pub fn id(&self) -> Option<ObjectId> {
+        match self {
+            ChatMessage::Direct(m) => m.id,
+            ChatMessage::Group(m) => m.id,
+            ChatMessage::System(m) => m.id,
+        }
+    }
+
+    // replies only show up inside their thread, not on the main timeline
+    pub fn in_thread(&self) -> bool {
+        matches!(self, ChatMessage::Group(m) if m.thread_id.is_some())
+    }
+
+    pub fn created_at(&self) -> Option<DateTime> {
+        match self {
+            ChatMessage::Direct(m) => m.created_at,
+            ChatMessage::Group(m) => m.created_at,
+            ChatMessage::System(m) => m.created_at,
+        }
+    }
+
+    // How a stored message is sent out: who hid it is nobody else's business,
+    // and reactions go out counted per emoji
+    pub fn for_clients(mut self) -> ChatMessage {
+        match &mut self {
+            ChatMessage::Direct(m) => {
+                m.hidden_for.clear();
+                m.reaction_counts = count_reactions(&m.reactions);
+                m.reactions.clear();
+            }
+            ChatMessage::Group(m) => {
+                m.hidden_for.clear();
+                m.reaction_counts = count_reactions(&m.reactions);
+                m.reactions.clear();
+            }
+            ChatMessage::System(_) => (),
+        }
+        self
+    }
+}
+
+// Pagination
+
+pub const DEFAULT_PAGE_LIMIT: i64 = 50;
+pub const MAX_PAGE_LIMIT: i64 = 100;
+
+// Raw query string of a history request, cursors are either an `_id` or an RFC 3339 `created_at`.
+// `before_seq`/`after_seq` page by message seq instead and take the place of `before`/`after`.
+#[derive(Deserialize, Debug, Default)]
+pub struct PageQuery {
+    pub before: Option<String>,
+    pub after: Option<String>,
+    pub before_seq: Option<i64>,
+    pub after_seq: Option<i64>,
+    pub limit: Option<i64>,
+}
+
+impl PageQuery {
+    pub fn parse(self) -> Result<PageRequest, String> {
+        if (self.before.is_some() && self.before_seq.is_some())
+            || (self.after.is_some() && self.after_seq.is_some())
+        {
+            return Err(String::from("use either a cursor or a seq on each side"));
+        }
+        let before = match self.before_seq {
+            Some(seq) => Some(PageCursor::Seq(seq)),
+            None => self.before.as_deref().map(PageCursor::parse).transpose()?,
+        };
+        let after = match self.after_seq {
+            Some(seq) => Some(PageCursor::Seq(seq)),
+            None => self.after.as_deref().map(PageCursor::parse).transpose()?,
+        };
+        let limit = self
+            .limit
+            .unwrap_or(DEFAULT_PAGE_LIMIT)
+            .clamp(1, MAX_PAGE_LIMIT);
+        Ok(PageRequest {
+            before,
+            after,
+            limit,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum PageCursor {
+    Id(ObjectId),
+    Time(DateTime),
+    Seq(i64),
+}
+
+impl PageCursor {
+    pub fn parse(s: &str) -> Result<PageCursor, String> {
+        if let Ok(id) = ObjectId::parse_str(s) {
+            return Ok(PageCursor::Id(id));
+        }
+        match DateTime::parse_rfc3339_str(s) {
+            Ok(t) => Ok(PageCursor::Time(t)),
+            Err(_) => Err(format!("invalid cursor : {}", s)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct PageRequest {
+    pub before: Option<PageCursor>,
+    pub after: Option<PageCursor>,
+    pub limit: i64,
+}
 
+// Items are always newest-first, the next page continues in the direction that was asked for.
+// A page asked for by seq goes on with `next_seq`, anything else with `next_cursor`, which is
+// always an `_id`, even after a `created_at` cursor: that only picks where to start,
+// several messages can share a timestamp so paging on by time could skip some.
+#[derive(Serialize, Debug)]
+pub struct Page<T> {
+    pub items: Vec<T>,
+    pub next_cursor: Option<ObjectId>,
+    pub next_seq: Option<i64>,
+}
+
+impl<T> Page<T> {
+    pub fn map<U>(self, f: impl FnMut(T) -> U) -> Page<U> {
+        Page {
+            items: self.items.into_iter().map(f).collect(),
+            next_cursor: self.next_cursor,
+            next_seq: self.next_seq,
+        }
+    }
+}
+
+pub trait Identified {
+    fn object_id(&self) -> Option<ObjectId>;
+    fn seq(&self) -> Option<i64> {
+        None
+    }
+}
+
+impl Identified for DirectMessage {
+    fn object_id(&self) -> Option<ObjectId> {
+        self.id
+    }
+    fn seq(&self) -> Option<i64> {
+        self.seq
+    }
+}
+
+impl Identified for Group {
+    fn object_id(&self) -> Option<ObjectId> {
+        self.id
+    }
+}
+
+impl Identified for GroupMessage {
+    fn object_id(&self) -> Option<ObjectId> {
+        self.id
+    }
+    fn seq(&self) -> Option<i64> {
+        self.seq
+    }
+}
+
+impl<M: Identified> Identified for TimelineEntry<M> {
+    fn object_id(&self) -> Option<ObjectId> {
+        match self {
+            TimelineEntry::System(m) => m.id,
+            TimelineEntry::Message(m) => m.object_id(),
+        }
+    }
+    fn seq(&self) -> Option<i64> {
+        match self {
+            TimelineEntry::System(m) => m.seq,
+            TimelineEntry::Message(m) => m.seq(),
+        }
+    }
+}
+
+// WebSocket Protocol
+//
+// Every frame is an envelope `{"v":1,"id":"..","op":"..","payload":{..}}`.
+// `id` is generated by the client and echoed back in the ack or error for that frame.
+
+pub const PROTOCOL_VERSION: u8 = 1;
+
+// `v` is checked before the frame is parsed so it is not kept here
+#[derive(Deserialize, Debug)]
+pub struct ClientFrame {
+    pub id: String,
+    #[serde(flatten)]
+    pub op: ClientOp,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(tag = "op", content = "payload", rename_all = "snake_case")]
+pub enum ClientOp {
+    Send(Box<ChatMessage>),
+    Edit(MessageEdit),
+    Delete(MessageDelete),
+    React(ReactionChange),
+    Unreact(ReactionChange),
+    // start or stop getting the replies of a thread as they are sent, for as long as the socket lives
+    SubscribeThread(ThreadRef),
+    UnsubscribeThread(ThreadRef),
+    // moves the caller's read watermark in that chat or group up to this message
+    MarkRead(MessageRef),
+    // never stored, only passed on to whoever else is in the conversation
+    TypingStarted(ConversationRef),
+    TypingStopped(ConversationRef),
+    // sent by clients while the user is active, keeps them from showing as away
+    Heartbeat,
+    Sync(SyncRequest),
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct ServerFrame {
+    pub v: u8,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+    #[serde(flatten)]
+    pub op: ServerOp,
+}
+
+impl ServerFrame {
+    pub fn new(id: Option<String>, op: ServerOp) -> ServerFrame {
+        ServerFrame {
+            v: PROTOCOL_VERSION,
+            id,
+            op,
+        }
+    }
+
+    pub fn message(msg: ChatMessage) -> ServerFrame {
+        ServerFrame::new(None, ServerOp::Message(Box::new(msg)))
+    }
+
+    pub fn event(event: ServerEvent) -> ServerFrame {
+        ServerFrame::new(None, ServerOp::Event(event))
+    }
+
+    pub fn error(id: Option<String>, err: FrameError) -> ServerFrame {
+        ServerFrame::new(id, ServerOp::Error(err))
+    }
+}
+
+#[derive(Serialize, Debug, Clone)]
+#[serde(tag = "op", content = "payload", rename_all = "snake_case")]
+pub enum ServerOp {
+    Message(Box<ChatMessage>),
+    Event(ServerEvent),
+    // one missed change while syncing
+    Change(Box<Change>),
+    Ack(Ack),
+    Error(FrameError),
+}
+
+// Things that happened which the client should redraw, `{"op":"event","payload":{"kind":..}}`
+#[derive(Serialize, Debug, Clone)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum ServerEvent {
+    GroupUpdated { group: Box<Group> },
+    MessageEdited { message: Box<ChatMessage> },
+    MessageDeleted { message: Box<ChatMessage> },
+    // only sent to the user who hid it, for their other devices
+    MessageHidden { message_id: ObjectId },
+    ReactionsUpdated { message: Box<ChatMessage> },
+    // the root of a thread after a reply, so every member can redraw its reply count
+    ThreadUpdated { message: Box<ChatMessage> },
+    Delivered { receipt: Receipt },
+    TypingStarted {
+        #[serde(flatten)]
+        target: ConversationRef,
+        user_id: ObjectId,
+    },
+    TypingStopped {
+        #[serde(flatten)]
+        target: ConversationRef,
+        user_id: ObjectId,
+    },
+    PresenceChanged { status: PresenceStatus },
+    // ends a sync, `seq` is where the next one starts from and `more` means it stopped early.
+    // `expired` and `resync` say what went past the change log and has to be refetched.
+    Synced {
+        seq: i64,
+        more: bool,
+        expired: bool,
+        resync: Vec<ConversationRef>,
+    },
+    // the receiver's new unread count for one chat or group
+    UnreadChanged {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        chat_id: Option<ObjectId>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        group_id: Option<ObjectId>,
+        unread: u64,
+    },
+    Read { receipt: Receipt },
+    JoinRequested { request: GroupJoinRequest },
+    JoinRequestHandled { request: GroupJoinRequest },
+}
+
+// Answers every request frame that went through. `_id` is the message it was about, left out
+// for requests not about one, and `created_at` when the server did it, which for a send is the
+// message's own timestamp.
+#[derive(Serialize, Debug, Clone)]
+pub struct Ack {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<ObjectId>,
+    pub created_at: DateTime,
+    // true when the client_msg_id was already used and this is the original message
+    pub duplicate: bool,
+}
+
+impl Ack {
+    pub fn applied(id: ObjectId) -> Ack {
+        Ack {
+            id: Some(id),
+            created_at: DateTime::now(),
+            duplicate: false,
+        }
+    }
+
+    pub fn received() -> Ack {
+        Ack {
+            id: None,
+            created_at: DateTime::now(),
+            duplicate: false,
+        }
+    }
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    BadFrame,
+    UnsupportedVersion,
+    InvalidPayload,
+    ChatNotFound,
+    GroupNotFound,
+    MessageNotFound,
+    Forbidden,
+    Internal,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct FrameError {
+    pub code: ErrorCode,
+    pub message: String,
+}
+
+impl ErrorCode {
+    // For the same errors coming back over REST
+    pub fn status(&self) -> StatusCode {
+        match self {
+            ErrorCode::BadFrame | ErrorCode::UnsupportedVersion | ErrorCode::InvalidPayload => {
+                StatusCode::BAD_REQUEST
+            }
+            ErrorCode::ChatNotFound | ErrorCode::GroupNotFound | ErrorCode::MessageNotFound => {
+                StatusCode::NOT_FOUND
+            }
+            ErrorCode::Forbidden => StatusCode::FORBIDDEN,
+            ErrorCode::Internal => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+impl FrameError {
+    pub fn new(code: ErrorCode, message: impl ToString) -> FrameError {
+        FrameError {
+            code,
+            message: message.to_string(),
+        }
+    }
 }
 
 // #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -330,14 +1765,27 @@ pub enum ChatMessage {
 pub struct MyError {
     error: String,
     location: String,
+    // the request was at fault, not the server
+    invalid: bool,
 }
 impl MyError {
     pub fn new<T: ToString>(error: T, location: T) -> MyError {
         MyError {
             error: error.to_string(),
             location: location.to_string(),
+            invalid: false,
+        }
+    }
+    pub fn invalid<T: ToString>(error: T, location: T) -> MyError {
+        MyError {
+            error: error.to_string(),
+            location: location.to_string(),
+            invalid: true,
         }
     }
+    pub fn is_invalid(&self) -> bool {
+        self.invalid
+    }
     #[allow(dead_code)]
     pub fn into_error(self) -> String {
         self.error
@@ -352,6 +1800,7 @@ impl MyError {
         MyError {
             error: e.to_string(),
             location: location.to_string(),
+            invalid: false,
         }
     }
 }
@@ -367,6 +1816,7 @@ impl From<mongodb::error::Error> for MyError {
         MyError {
             error: value.to_string(),
             location: "don't know".to_string(),
+            invalid: false,
         }
     }
 }
@@ -398,3 +1848,290 @@ pub struct FromUser {
 //     pub email: String,
 //     pub value: usize,
 // }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn session(refresh_jti: &str) -> Session {
+        let expires_at = DateTime::from_millis(DateTime::now().timestamp_millis() + 60_000);
+        Session::new(
+            ObjectId::new(),
+            ObjectId::new(),
+            refresh_jti.to_string(),
+            DeviceInfo::default(),
+            expires_at,
+        )
+    }
+
+    fn after(t: DateTime, d: Duration) -> DateTime {
+        DateTime::from_millis(t.timestamp_millis() + d.as_millis() as i64)
+    }
+
+    #[test]
+    fn current_refresh_token_rotates() {
+        let s = session("a");
+        let grace = Duration::from_secs(30);
+        assert_eq!(s.check_refresh("a", DateTime::now(), grace), RefreshCheck::Rotate);
+        assert_eq!(s.check_refresh("b", DateTime::now(), grace), RefreshCheck::Reuse);
+    }
+
+    #[test]
+    fn rotated_token_is_replayed_only_inside_the_grace_window() {
+        let grace = Duration::from_secs(30);
+        let mut s = session("b");
+        let rotated = DateTime::now();
+        s.previous_jti = Some(String::from("a"));
+        s.rotated_at = Some(rotated);
+        let inside = after(rotated, grace / 2);
+        assert_eq!(
+            s.check_refresh("a", inside, grace),
+            RefreshCheck::Replay(String::from("b"))
+        );
+        assert_eq!(s.check_refresh("a", after(rotated, grace), grace), RefreshCheck::Reuse);
+        // older tokens than the previous one are always reuse
+        assert_eq!(s.check_refresh("z", inside, grace), RefreshCheck::Reuse);
+    }
+
+    #[test]
+    fn dead_sessions_never_refresh() {
+        let grace = Duration::from_secs(30);
+        let mut s = session("a");
+        s.revoked = true;
+        assert_eq!(s.check_refresh("a", DateTime::now(), grace), RefreshCheck::Reuse);
+        let s = session("a");
+        let expired = after(s.expires_at, Duration::from_secs(1));
+        assert_eq!(s.check_refresh("a", expired, grace), RefreshCheck::Reuse);
+    }
+
+    fn group_with(roles: &[(ObjectId, GroupRole)]) -> Group {
+        let details = NewGroup {
+            members: HashSet::new(),
+            name: String::from("group"),
+            description: None,
+            avatar: None,
+            settings: GroupSettings::default(),
+        };
+        let owner = roles[0].0;
+        let mut group = Group::new(owner, roles.iter().map(|(u, _)| *u).collect(), details);
+        for (user, role) in roles {
+            group.roles.insert(user.to_hex(), *role);
+        }
+        group
+    }
+
+    #[test]
+    fn roles_rank_above_the_ones_below() {
+        let (owner, admin, moderator, member, outsider) = (
+            ObjectId::new(),
+            ObjectId::new(),
+            ObjectId::new(),
+            ObjectId::new(),
+            ObjectId::new(),
+        );
+        let group = group_with(&[
+            (owner, GroupRole::Owner),
+            (admin, GroupRole::Admin),
+            (moderator, GroupRole::Moderator),
+            (member, GroupRole::Member),
+        ]);
+        assert!(group.outranks(owner, admin));
+        assert!(group.outranks(admin, moderator));
+        assert!(group.outranks(moderator, member));
+        assert!(!group.outranks(moderator, admin));
+        assert!(!group.outranks(member, member));
+        // anyone in the group outranks someone who is not, nobody outside outranks anyone
+        assert!(group.outranks(member, outsider));
+        assert!(!group.outranks(outsider, member));
+    }
+
+    #[test]
+    fn permissions_follow_role_and_settings() {
+        let (owner, admin, moderator, member) =
+            (ObjectId::new(), ObjectId::new(), ObjectId::new(), ObjectId::new());
+        let mut group = group_with(&[
+            (owner, GroupRole::Owner),
+            (admin, GroupRole::Admin),
+            (moderator, GroupRole::Moderator),
+            (member, GroupRole::Member),
+        ]);
+        assert!(group.can(member, GroupPermission::Post));
+        assert!(!group.can(member, GroupPermission::AddMembers));
+        assert!(group.can(moderator, GroupPermission::DeleteMessages));
+        assert!(!group.can(member, GroupPermission::DeleteMessages));
+        assert!(!group.can(admin, GroupPermission::TransferOwnership));
+        assert!(group.can(owner, GroupPermission::TransferOwnership));
+        assert!(!group.can(ObjectId::new(), GroupPermission::Post));
+
+        group.settings.who_can_post = GroupPolicy::Admins;
+        assert!(!group.can(moderator, GroupPermission::Post));
+        assert!(group.can(admin, GroupPermission::Post));
+    }
+
+    fn new_invite(max_uses: Option<u32>, expires_in: Option<u64>) -> GroupInvite {
+        let details = NewInvite {
+            group_id: ObjectId::new(),
+            expires_in,
+            max_uses,
+        };
+        GroupInvite::new(String::from("token"), ObjectId::new(), details)
+    }
+
+    #[test]
+    fn invite_stops_working_at_expiry() {
+        let invite = new_invite(None, Some(60));
+        let expires_at = invite.expires_at.unwrap();
+        let before = DateTime::from_millis(expires_at.timestamp_millis() - 1);
+        assert!(invite.usable(before));
+        assert!(!invite.usable(expires_at));
+        assert!(invite.usable(DateTime::now()));
+    }
+
+    #[test]
+    fn invite_stops_working_at_its_use_limit() {
+        let mut invite = new_invite(Some(2), None);
+        let now = DateTime::now();
+        assert!(invite.usable(now));
+        invite.uses = 1;
+        assert!(invite.usable(now));
+        invite.uses = 2;
+        assert!(!invite.usable(now));
+
+        let mut unlimited = new_invite(None, None);
+        unlimited.uses = 1000;
+        assert!(unlimited.usable(now));
+        unlimited.revoked = true;
+        assert!(!unlimited.usable(now));
+    }
+
+    #[test]
+    fn seq_orders_watermarks_even_against_the_ids() {
+        let (first, second) = (ObjectId::new(), ObjectId::new());
+        let at = |message_id, seq| Watermark { message_id, seq };
+        // `second` was stored later but got the earlier seq
+        assert!(at(second, Some(4)).is_before(&at(first, Some(5))));
+        assert!(!at(first, Some(5)).is_before(&at(second, Some(4))));
+        assert!(!at(first, Some(5)).is_before(&at(first, Some(5))));
+    }
+
+    #[test]
+    fn watermarks_without_seq_go_by_id() {
+        let (first, second) = (ObjectId::new(), ObjectId::new());
+        let at = |message_id, seq| Watermark { message_id, seq };
+        // a marker from before seqs existed
+        assert!(at(first, None).is_before(&at(second, Some(1))));
+        assert!(!at(second, None).is_before(&at(first, Some(9))));
+        // a thread reply never moves a marker that has a seq
+        assert!(!at(first, Some(1)).is_before(&at(second, None)));
+        let first_raise = WatermarkMove {
+            from: None,
+            to: at(first, None),
+        };
+        assert!(first_raise.advanced());
+    }
+
+    #[test]
+    fn page_query_parses_each_cursor_kind() {
+        let id = ObjectId::new();
+        let page = PageQuery {
+            after: Some(id.to_hex()),
+            ..Default::default()
+        }
+        .parse()
+        .unwrap();
+        assert!(matches!(page.after, Some(PageCursor::Id(a)) if a == id));
+        assert!(page.before.is_none());
+
+        let page = PageQuery {
+            before: Some(String::from("2025-01-02T03:04:05Z")),
+            ..Default::default()
+        }
+        .parse()
+        .unwrap();
+        assert!(matches!(page.before, Some(PageCursor::Time(_))));
+
+        let bad = PageQuery {
+            after: Some(String::from("yesterday")),
+            ..Default::default()
+        };
+        assert!(bad.parse().is_err());
+    }
+
+    #[test]
+    fn page_query_clamps_the_limit() {
+        let parse = |limit| PageQuery { limit, ..Default::default() }.parse().unwrap().limit;
+        assert_eq!(parse(None), DEFAULT_PAGE_LIMIT);
+        assert_eq!(parse(Some(0)), 1);
+        assert_eq!(parse(Some(MAX_PAGE_LIMIT + 1)), MAX_PAGE_LIMIT);
+    }
+
+    #[test]
+    fn reactions_are_counted_in_first_use_order() {
+        let (a, b, c) = (ObjectId::new(), ObjectId::new(), ObjectId::new());
+        let reaction = |emoji: &str, user_id| Reaction {
+            emoji: emoji.to_string(),
+            user_id,
+        };
+        let counts = count_reactions(&[
+            reaction("👍", a),
+            reaction("🎉", b),
+            reaction("👍", c),
+            reaction("😂", a),
+            reaction("🎉", a),
+        ]);
+        let summary: Vec<_> = counts
+            .iter()
+            .map(|c| (c.emoji.as_str(), c.count, c.users.clone()))
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                ("👍", 2, vec![a, c]),
+                ("🎉", 2, vec![b, a]),
+                ("😂", 1, vec![a]),
+            ]
+        );
+        assert!(count_reactions(&[]).is_empty());
+    }
+
+    #[test]
+    fn only_single_emoji_are_reactions() {
+        for emoji in ["👍", "👍🏽", "👨‍👩‍👧", "🇮🇳", "❤️"] {
+            assert!(Reaction::is_emoji(emoji), "{}", emoji);
+        }
+        for text in ["", "hello", "<script>", "👍👍", "👍 ", ":+1:"] {
+            assert!(!Reaction::is_emoji(text), "{}", text);
+        }
+    }
+
+    #[test]
+    fn page_query_rejects_cursor_and_seq_on_one_side() {
+        let id = ObjectId::new().to_hex();
+        let before = PageQuery {
+            before: Some(id.clone()),
+            before_seq: Some(3),
+            ..Default::default()
+        };
+        assert!(before.parse().is_err());
+        let after = PageQuery {
+            after: Some(id),
+            after_seq: Some(3),
+            ..Default::default()
+        };
+        assert!(after.parse().is_err());
+    }
+
+    #[test]
+    fn page_query_mixes_seq_and_cursor_on_different_sides() {
+        let id = ObjectId::new();
+        let page = PageQuery {
+            before_seq: Some(9),
+            after: Some(id.to_hex()),
+            ..Default::default()
+        }
+        .parse()
+        .unwrap();
+        assert!(matches!(page.before, Some(PageCursor::Seq(9))));
+        assert!(matches!(page.after, Some(PageCursor::Id(a)) if a == id));
+    }
+}
@@ -1,6 +1,6 @@
 use axum::{
     body::{to_bytes, Body},
-    extract::{Path, Request},
+    extract::{Path, Query, Request},
     http::StatusCode,
     response::IntoResponse,
     Extension, Json,
@@ -9,17 +9,27 @@ use bson::{oid::ObjectId, Bson};
 use log::{debug, error, info};
 use std::{sync::Arc, usize};
 // use mongodb::bson::oid::ObjectId;
-use serde_json::{from_str, json};
+use serde_json::{from_slice, from_str, json};
+use tokio::sync::Mutex;
 
 use crate::{
+    authz,
     db::Db,
-    models::{FriendReq, FriendRequest, MyError, Requests},
+    models::{
+        ChatMessage, FriendReq, FriendRequest, GroupPermission, MessageDelete, MessageEdit,
+        MessageRef, MyError, PageQuery, Presence, PresenceQuery, PresenceStatus, Requests, ThreadRef,
+        MAX_PRESENCE_QUERY,
+    },
+    routes::chat::{
+        delete_chat_message, edit_chat_message, mark_fetched, send_chat_message, thread_root,
+        Manager,
+    },
     utils::{extract_cookie, extract_cookie_into_user},
 };
 
 pub async fn get_chats(Extension(db): Extension<Arc<Db>>, req: Request<Body>) -> impl IntoResponse {
     let (parts, _) = req.into_parts();
-    let jwt = extract_cookie(parts).await;
+    let jwt = extract_cookie(parts, &db).await;
     match jwt {
         Ok(claims) => {
             let requests = db.get_chats(claims.sub).await;
@@ -58,15 +68,46 @@ pub async fn get_chats(Extension(db): Extension<Arc<Db>>, req: Request<Body>) ->
 
 pub async fn get_messages(
     Extension(db): Extension<Arc<Db>>,
+    Extension(manager): Extension<Arc<Mutex<Manager>>>,
     Path(chat_id):Path<ObjectId>,
-    _: Request<Body>,
+    Query(query): Query<PageQuery>,
+    req: Request<Body>,
 ) -> impl IntoResponse {
-    let res = db.get_messages_with_chat_id(chat_id).await;
+    let (parts, _) = req.into_parts();
+    let claims = match extract_cookie(parts, &db).await {
+        Ok(c) => c,
+        Err(e) => {
+            return (StatusCode::UNAUTHORIZED,Json(json!({
+                "err":e
+            })))
+        }
+    };
+    if let Err(e) = authz::chat_participant(&db, &claims.sub, chat_id).await {
+        return (e.code.status(),Json(json!({
+            "err":e
+        })));
+    }
+    let page = match query.parse() {
+        Ok(p) => p,
+        Err(e) => {
+            return (StatusCode::BAD_REQUEST,Json(json!({
+                "err":e
+            })))
+        }
+    };
+    let viewer = ObjectId::parse_str(&claims.sub).unwrap();
+    let res = db.get_messages_with_chat_id(chat_id, viewer, &page).await;
 
     match res {
-        Ok(messages) => {
+        Ok(page) => {
+            if let Some(newest) = page.items.iter().filter_map(ChatMessage::id).max() {
+                let target = MessageRef::Direct { chat_id, message_id: newest };
+                mark_fetched(&db, &manager, &claims.sub, target).await;
+            }
             (StatusCode::OK,Json(json!({
-                "messages":messages
+                "messages":page.items,
+                "next_cursor":page.next_cursor.map(|id| id.to_hex()),
+                "next_seq":page.next_seq
             })))
         }
         Err(e) => {
@@ -78,12 +119,256 @@ pub async fn get_messages(
     }
 }
 
+pub async fn get_groups(
+    Extension(db): Extension<Arc<Db>>,
+    Query(query): Query<PageQuery>,
+    req: Request<Body>,
+) -> impl IntoResponse {
+    let (parts, _) = req.into_parts();
+    let claims = match extract_cookie(parts, &db).await {
+        Ok(c) => c,
+        Err(e) => {
+            return (
+                StatusCode::UNAUTHORIZED,
+                Json(json!({
+                    "err":e
+                })),
+            )
+        }
+    };
+    let page = match query.parse() {
+        Ok(p) => p,
+        Err(e) => {
+            return (
+                StatusCode::BAD_REQUEST,
+                Json(json!({
+                    "err":e
+                })),
+            )
+        }
+    };
+    let res = db.get_groups(claims.sub, &page).await;
+    match res {
+        Ok(page) => (
+            StatusCode::OK,
+            Json(json!({
+                "groups":page.items,
+                "next_cursor":page.next_cursor.map(|id| id.to_hex())
+            })),
+        ),
+        Err(e) => {
+            error!("error in get groups {}", e);
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({
+                    "err":e.error()
+                })),
+            )
+        }
+    }
+}
+
+pub async fn get_group_messages(
+    Extension(db): Extension<Arc<Db>>,
+    Extension(manager): Extension<Arc<Mutex<Manager>>>,
+    Path(group_id): Path<ObjectId>,
+    Query(query): Query<PageQuery>,
+    req: Request<Body>,
+) -> impl IntoResponse {
+    let (parts, _) = req.into_parts();
+    let claims = match extract_cookie(parts, &db).await {
+        Ok(c) => c,
+        Err(e) => {
+            return (
+                StatusCode::UNAUTHORIZED,
+                Json(json!({
+                    "err":e
+                })),
+            )
+        }
+    };
+    if let Err(e) = authz::group_member(&db, &claims.sub, group_id).await {
+        return (
+            e.code.status(),
+            Json(json!({
+                "err":e
+            })),
+        );
+    }
+    let page = match query.parse() {
+        Ok(p) => p,
+        Err(e) => {
+            return (
+                StatusCode::BAD_REQUEST,
+                Json(json!({
+                    "err":e
+                })),
+            )
+        }
+    };
+    let viewer = ObjectId::parse_str(&claims.sub).unwrap();
+    let res = db.get_group_messages(group_id, viewer, &page).await;
+    match res {
+        Ok(page) => {
+            if let Some(newest) = page.items.iter().filter_map(ChatMessage::id).max() {
+                let target = MessageRef::Group {
+                    group_id,
+                    message_id: newest,
+                };
+                mark_fetched(&db, &manager, &claims.sub, target).await;
+            }
+            (
+                StatusCode::OK,
+                Json(json!({
+                    "messages":page.items,
+                    "next_cursor":page.next_cursor.map(|id| id.to_hex()),
+                    "next_seq":page.next_seq
+                })),
+            )
+        }
+        Err(e) => {
+            error!("{}", e);
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({
+                    "err":e.error()
+                })),
+            )
+        }
+    }
+}
+
+pub async fn get_thread_replies(
+    Extension(db): Extension<Arc<Db>>,
+    Path((group_id, thread_id)): Path<(ObjectId, ObjectId)>,
+    Query(query): Query<PageQuery>,
+    req: Request<Body>,
+) -> impl IntoResponse {
+    let (parts, _) = req.into_parts();
+    let claims = match extract_cookie(parts, &db).await {
+        Ok(c) => c,
+        Err(e) => {
+            return (
+                StatusCode::UNAUTHORIZED,
+                Json(json!({
+                    "err":e
+                })),
+            )
+        }
+    };
+    let thread = ThreadRef {
+        group_id,
+        thread_id,
+    };
+    if let Err(e) = authz::group_member(&db, &claims.sub, group_id).await {
+        return (
+            e.code.status(),
+            Json(json!({
+                "err":e
+            })),
+        );
+    }
+    let root = match thread_root(&db, thread).await {
+        Ok(root) => ChatMessage::Group(root).for_clients(),
+        Err(e) => {
+            return (
+                e.code.status(),
+                Json(json!({
+                    "err":e
+                })),
+            )
+        }
+    };
+    let page = match query.parse() {
+        Ok(p) => p,
+        Err(e) => {
+            return (
+                StatusCode::BAD_REQUEST,
+                Json(json!({
+                    "err":e
+                })),
+            )
+        }
+    };
+    let viewer = ObjectId::parse_str(&claims.sub).unwrap();
+    let res = db.get_thread_replies(thread, viewer, &page).await;
+    match res {
+        Ok(page) => (
+            StatusCode::OK,
+            Json(json!({
+                "root":root,
+                "messages":page.items,
+                "next_cursor":page.next_cursor.map(|id| id.to_hex()),
+                "next_seq":page.next_seq
+            })),
+        ),
+        Err(e) => {
+            error!("{}", e);
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({
+                    "err":e.error()
+                })),
+            )
+        }
+    }
+}
+
+// Same path as the WebSocket send, so a retried request with the same client_msg_id is not stored twice
+pub async fn send_message(
+    Extension(db): Extension<Arc<Db>>,
+    Extension(manager): Extension<Arc<Mutex<Manager>>>,
+    req: Request<Body>,
+) -> impl IntoResponse {
+    let (parts, body) = req.into_parts();
+    let claims = match extract_cookie(parts, &db).await {
+        Ok(c) => c,
+        Err(e) => {
+            return (
+                StatusCode::UNAUTHORIZED,
+                Json(json!({
+                    "err":e
+                })),
+            )
+        }
+    };
+    let bytes = to_bytes(body, usize::MAX).await.unwrap();
+    let msg = match from_slice::<ChatMessage>(&bytes) {
+        Ok(m) => m,
+        Err(e) => {
+            error!("{}", e);
+            return (
+                StatusCode::BAD_REQUEST,
+                Json(json!({
+                    "err":"invalid request body"
+                })),
+            );
+        }
+    };
+    let res = send_chat_message(&db, &manager, &claims.sub, None, msg).await;
+    match res {
+        Ok(ack) => (
+            StatusCode::OK,
+            Json(json!({
+                "success":true,
+                "ack":ack
+            })),
+        ),
+        Err(e) => (
+            e.code.status(),
+            Json(json!({
+                "err":e
+            })),
+        ),
+    }
+}
+
 pub async fn get_friend_request(
     Extension(db): Extension<Arc<Db>>,
     req: Request<Body>,
 ) -> impl IntoResponse {
     let (parts, _) = req.into_parts();
-    let jwt = extract_cookie(parts).await;
+    let jwt = extract_cookie(parts, &db).await;
     match jwt {
         Ok(claims) => {
             let requests = db.fetch_user_friend_request(claims.sub).await;
@@ -122,7 +407,7 @@ pub async fn handle_friend_request(
     req: Request<Body>,
 ) -> impl IntoResponse {
     let (parts, body) = req.into_parts();
-    let id = extract_cookie(parts).await.unwrap().sub;
+    let id = extract_cookie(parts, &db).await.unwrap().sub;
     let bytes = to_bytes(body, usize::MAX).await.unwrap();
     let data = String::from_utf8_lossy(&bytes);
     let request = from_str::<FriendRequest>(&data).unwrap();
@@ -163,6 +448,195 @@ pub async fn handle_friend_request(
     }
 }
 
+pub async fn edit_message(
+    Extension(db): Extension<Arc<Db>>,
+    Extension(manager): Extension<Arc<Mutex<Manager>>>,
+    req: Request<Body>,
+) -> impl IntoResponse {
+    let (parts, body) = req.into_parts();
+    let claims = match extract_cookie(parts, &db).await {
+        Ok(c) => c,
+        Err(e) => {
+            return (
+                StatusCode::UNAUTHORIZED,
+                Json(json!({
+                    "err":e
+                })),
+            )
+        }
+    };
+    let bytes = to_bytes(body, usize::MAX).await.unwrap();
+    let edit = match from_slice::<MessageEdit>(&bytes) {
+        Ok(e) => e,
+        Err(e) => {
+            error!("{}", e);
+            return (
+                StatusCode::BAD_REQUEST,
+                Json(json!({
+                    "err":"invalid request body"
+                })),
+            );
+        }
+    };
+    let res = edit_chat_message(&db, &manager, &claims.sub, None, edit).await;
+    match res {
+        Ok(message) => (
+            StatusCode::OK,
+            Json(json!({
+                "success":true,
+                "message":message
+            })),
+        ),
+        Err(e) => (
+            e.code.status(),
+            Json(json!({
+                "err":e
+            })),
+        ),
+    }
+}
+
+pub async fn delete_message(
+    Extension(db): Extension<Arc<Db>>,
+    Extension(manager): Extension<Arc<Mutex<Manager>>>,
+    req: Request<Body>,
+) -> impl IntoResponse {
+    let (parts, body) = req.into_parts();
+    let claims = match extract_cookie(parts, &db).await {
+        Ok(c) => c,
+        Err(e) => {
+            return (
+                StatusCode::UNAUTHORIZED,
+                Json(json!({
+                    "err":e
+                })),
+            )
+        }
+    };
+    let bytes = to_bytes(body, usize::MAX).await.unwrap();
+    let delete = match from_slice::<MessageDelete>(&bytes) {
+        Ok(d) => d,
+        Err(e) => {
+            error!("{}", e);
+            return (
+                StatusCode::BAD_REQUEST,
+                Json(json!({
+                    "err":"invalid request body"
+                })),
+            );
+        }
+    };
+    let res = delete_chat_message(&db, &manager, &claims.sub, None, delete).await;
+    match res {
+        Ok(event) => (
+            StatusCode::OK,
+            Json(json!({
+                "success":true,
+                "event":event
+            })),
+        ),
+        Err(e) => (
+            e.code.status(),
+            Json(json!({
+                "err":e
+            })),
+        ),
+    }
+}
+
+// Presence of the given users for the chat list, anyone who is not a friend is left out
+pub async fn get_presence(
+    Extension(db): Extension<Arc<Db>>,
+    Extension(manager): Extension<Arc<Mutex<Manager>>>,
+    req: Request<Body>,
+) -> impl IntoResponse {
+    let (parts, body) = req.into_parts();
+    let claims = match extract_cookie(parts, &db).await {
+        Ok(c) => c,
+        Err(e) => {
+            return (
+                StatusCode::UNAUTHORIZED,
+                Json(json!({
+                    "err":e
+                })),
+            )
+        }
+    };
+    let bytes = to_bytes(body, usize::MAX).await.unwrap();
+    let query = match from_slice::<PresenceQuery>(&bytes) {
+        Ok(q) if q.user_ids.len() <= MAX_PRESENCE_QUERY => q,
+        Ok(_) => {
+            return (
+                StatusCode::BAD_REQUEST,
+                Json(json!({
+                    "err":format!("at most {} users at a time", MAX_PRESENCE_QUERY)
+                })),
+            )
+        }
+        Err(e) => {
+            error!("{}", e);
+            return (
+                StatusCode::BAD_REQUEST,
+                Json(json!({
+                    "err":"invalid request body"
+                })),
+            );
+        }
+    };
+    let user = ObjectId::parse_str(&claims.sub).unwrap();
+    let friends = match db.get_friend_ids(user).await {
+        Ok(f) => f,
+        Err(e) => {
+            error!("{}", e);
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({
+                    "err":e.error()
+                })),
+            );
+        }
+    };
+    let users: Vec<ObjectId> = query
+        .user_ids
+        .into_iter()
+        .filter(|id| friends.contains(id))
+        .collect();
+    let last_seen = match db.get_last_seen(&users).await {
+        Ok(s) => s,
+        Err(e) => {
+            error!("{}", e);
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({
+                    "err":e.error()
+                })),
+            );
+        }
+    };
+    let manager = manager.lock().await;
+    let statuses: Vec<PresenceStatus> = users
+        .into_iter()
+        .map(|id| {
+            let presence = manager.presence_of(&id.to_hex());
+            PresenceStatus {
+                user_id: id,
+                presence,
+                // it only means something once they are gone
+                last_seen: match presence {
+                    Presence::Online => None,
+                    _ => last_seen.get(&id).copied(),
+                },
+            }
+        })
+        .collect();
+    (
+        StatusCode::OK,
+        Json(json!({
+            "presence":statuses
+        })),
+    )
+}
+
 pub async fn get_my_id(Extension(db): Extension<Arc<Db>>,req: Request<Body>) -> impl IntoResponse{
     let (parts , _) = req.into_parts();
     let user = extract_cookie_into_user(&parts, &db).await;
@@ -191,7 +665,7 @@ pub async fn handle_incoming_request(Extension(db): Extension<Arc<Db>>,req: Requ
     let (parts , body) = req.into_parts();
     let bytes = to_bytes(body, usize::MAX).await.unwrap();
     let data = String::from_utf8_lossy(&bytes).to_string();
-    let id = extract_cookie(parts).await.unwrap().sub;
+    let id = extract_cookie(parts, &db).await.unwrap().sub;
     let req = from_str::<FriendReq>(&data).unwrap();
     let request = Requests::new_from_friend_req(req, id);
     let res = db.add_friend_request(request).await;
@@ -209,4 +683,159 @@ pub async fn handle_incoming_request(Extension(db): Extension<Arc<Db>>,req: Requ
             })))
         }
     }
-}
\ No newline at end of file
+}
+
+pub async fn get_group_invites(
+    Extension(db): Extension<Arc<Db>>,
+    Path(group_id): Path<ObjectId>,
+    req: Request<Body>,
+) -> impl IntoResponse {
+    let (parts, _) = req.into_parts();
+    let claims = match extract_cookie(parts, &db).await {
+        Ok(c) => c,
+        Err(e) => {
+            return (
+                StatusCode::UNAUTHORIZED,
+                Json(json!({
+                    "err":e
+                })),
+            )
+        }
+    };
+    if let Err(e) = authz::group_permission(
+        &db,
+        &claims.sub,
+        group_id,
+        GroupPermission::ManageInvites,
+        "only admins can list the group's invites",
+    )
+    .await
+    {
+        return (
+            e.code.status(),
+            Json(json!({
+                "err":e
+            })),
+        );
+    }
+    match db.get_group_invites(group_id).await {
+        Ok(invites) => (
+            StatusCode::OK,
+            Json(json!({
+                "invites":invites
+            })),
+        ),
+        Err(e) => {
+            error!("{}", e);
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({
+                    "err":e.error()
+                })),
+            )
+        }
+    }
+}
+
+// Watermarks of everyone still in the group, members without one have not read anything yet
+pub async fn get_read_markers(
+    Extension(db): Extension<Arc<Db>>,
+    Path(group_id): Path<ObjectId>,
+    req: Request<Body>,
+) -> impl IntoResponse {
+    let (parts, _) = req.into_parts();
+    let claims = match extract_cookie(parts, &db).await {
+        Ok(c) => c,
+        Err(e) => {
+            return (
+                StatusCode::UNAUTHORIZED,
+                Json(json!({
+                    "err":e
+                })),
+            )
+        }
+    };
+    let group = match authz::group_member(&db, &claims.sub, group_id).await {
+        Ok(g) => g,
+        Err(e) => {
+            return (
+                e.code.status(),
+                Json(json!({
+                    "err":e
+                })),
+            )
+        }
+    };
+    match db.get_read_markers(group_id).await {
+        Ok(mut markers) => {
+            markers.retain(|m| group.members.contains(&m.user_id));
+            (
+                StatusCode::OK,
+                Json(json!({
+                    "markers":markers
+                })),
+            )
+        }
+        Err(e) => {
+            error!("{}", e);
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({
+                    "err":e.error()
+                })),
+            )
+        }
+    }
+}
+
+pub async fn get_join_requests(
+    Extension(db): Extension<Arc<Db>>,
+    Path(group_id): Path<ObjectId>,
+    req: Request<Body>,
+) -> impl IntoResponse {
+    let (parts, _) = req.into_parts();
+    let claims = match extract_cookie(parts, &db).await {
+        Ok(c) => c,
+        Err(e) => {
+            return (
+                StatusCode::UNAUTHORIZED,
+                Json(json!({
+                    "err":e
+                })),
+            )
+        }
+    };
+    if let Err(e) = authz::group_permission(
+        &db,
+        &claims.sub,
+        group_id,
+        GroupPermission::HandleJoinRequests,
+        "only admins can see join requests",
+    )
+    .await
+    {
+        return (
+            e.code.status(),
+            Json(json!({
+                "err":e
+            })),
+        );
+    }
+    match db.get_pending_join_requests(group_id).await {
+        Ok(requests) => (
+            StatusCode::OK,
+            Json(json!({
+                "requests":requests
+            })),
+        ),
+        Err(e) => {
+            error!("{}", e);
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({
+                    "err":e.error()
+                })),
+            )
+        }
+    }
+}
@@ -1,30 +1,35 @@
-use crate::{db::Db, models::*, utils::extract_cookie_into_user};
+use crate::{
+    db::Db,
+    models::*,
+    routes::chat::Manager,
+    utils::{
+        create_access_token, create_refresh_token, device_info, extract_cookie,
+        extract_cookie_into_user, find_cookie, new_token_id, validate_refresh_jwt, ACCESS_COOKIE,
+        REFRESH_COOKIE, REFRESH_GRACE, REFRESH_TOKEN_TTL,
+    },
+};
 use axum::{
     body::{to_bytes, Body},
-    extract::Request,
+    extract::{ConnectInfo, Path, Request},
     http::{
         header::{self},
-        HeaderMap, HeaderValue, StatusCode,
+        HeaderMap, StatusCode,
     },
     response::IntoResponse,
     Extension, Json,
 };
-use cookie::{time::Duration as Samay, Cookie, CookieBuilder};
-use jsonwebtoken::{encode, EncodingKey, Header};
+use bson::{oid::ObjectId, DateTime};
+use cookie::{time::Duration as Samay, Cookie};
 // use lettre::AsyncTransport;
 // use lettre::{
 //     message::header::ContentType, transport::smtp::authentication::Credentials, AsyncSmtpTransport,
 //     Message, Tokio1Executor,
 // };
-use log::{debug, error, info};
+use log::{debug, error, info, warn};
 // use rand::Rng;
 use serde_json::{from_str, from_value, json, Value};
-use std::{
-    env,
-    sync::Arc,
-    time::{Duration, SystemTime, UNIX_EPOCH},
-    usize,
-};
+use std::{env, net::SocketAddr, sync::Arc, time::SystemTime};
+use tokio::sync::Mutex;
 
 // Tried many things for the code to be able to work on render but it does not let me use the smtp protocol
 
@@ -123,7 +128,7 @@ pub async fn signup(Extension(db): Extension<Arc<Db>>, req: Request<Body>) -> im
             )
         }
         Err(e) => {
-            info!("{}", e.to_string());
+            info!("{}", e);
             (
                 StatusCode::INTERNAL_SERVER_ERROR,
                 Json(json!({
@@ -134,37 +139,102 @@ pub async fn signup(Extension(db): Extension<Arc<Db>>, req: Request<Body>) -> im
     }
 }
 
+fn session_cookies(access: &str, refresh: &str) -> HeaderMap {
+    let access = Cookie::build((ACCESS_COOKIE, access.to_string()))
+        .path("/")
+        .http_only(true)
+        .same_site(cookie::SameSite::None)
+        .secure(true)
+        .build();
+    let refresh = Cookie::build((REFRESH_COOKIE, refresh.to_string()))
+        .path("/auth")
+        .max_age(Samay::seconds(REFRESH_TOKEN_TTL.as_secs() as i64))
+        .http_only(true)
+        .same_site(cookie::SameSite::None)
+        .secure(true)
+        .build();
+    let mut headers = HeaderMap::new();
+    headers.append(header::SET_COOKIE, access.to_string().parse().unwrap());
+    headers.append(header::SET_COOKIE, refresh.to_string().parse().unwrap());
+    headers
+}
+
+fn clear_session_cookies() -> HeaderMap {
+    let http = match env::var("ENV").unwrap_or_default().as_str() {
+        "development" => true,
+        "production" => false,
+        _ => {
+            debug!("no variable named ENV found deafaulting to development");
+            true
+        }
+    };
+    let mut headers = HeaderMap::new();
+    for (name, path) in [(ACCESS_COOKIE, "/"), (REFRESH_COOKIE, "/auth")] {
+        let cookie = Cookie::build((name, ""))
+            .path(path)
+            .max_age(Samay::ZERO)
+            .http_only(http)
+            .same_site(cookie::SameSite::None)
+            .secure(true)
+            .build();
+        headers.append(header::SET_COOKIE, cookie.to_string().parse().unwrap());
+    }
+    headers
+}
+
+// Mints a new access token and a refresh token carrying `jti` for the given session
+fn issue_tokens(user_id: &str, session_id: &str, jti: &str) -> Result<(String, HeaderMap), MyError> {
+    let access = create_access_token(user_id, session_id)?;
+    let refresh = create_refresh_token(user_id, session_id, jti)?;
+    let headers = session_cookies(&access, &refresh);
+    Ok((access, headers))
+}
+
 pub async fn login(
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
     Extension(db): Extension<Arc<Db>>,
+    headers: HeaderMap,
     Json(body): Json<Value>,
 ) -> impl IntoResponse {
     let data: LoginUser = from_value(body).unwrap();
     let user = db.login_user(&data).await;
     match user {
         Some(u) => {
-            let exp = SystemTime::now().duration_since(UNIX_EPOCH).unwrap()
-                + Duration::from_secs(28 * 24 * 3600);
-            let claims = &Claims {
-                sub: u.id.unwrap().to_hex(),
-                exp: exp.as_secs() as usize,
+            let user_id = u.id.unwrap();
+            let session_id = ObjectId::new();
+            let jti = new_token_id();
+            let expires_at = DateTime::from_system_time(SystemTime::now() + REFRESH_TOKEN_TTL);
+            let device = device_info(&headers, addr);
+            let session = Session::new(session_id, user_id, jti.clone(), device, expires_at);
+            let tokens = match db.create_session(session).await {
+                Ok(()) => issue_tokens(&user_id.to_hex(), &session_id.to_hex(), &jti),
+                Err(e) => Err(e),
             };
-            let secret = env::var("JWT_SECRET").unwrap();
-            let key = &EncodingKey::from_secret(secret.as_bytes());
-            let token = encode(&Header::new(jsonwebtoken::Algorithm::HS256), claims, key);
-            let mut headers = HeaderMap::new();
-            let t = token.unwrap();
-            let value = format!("jwt={}; HttpOnly; Path=/;SameSite=None;Secure;", t.clone());
-            headers.append(header::SET_COOKIE, HeaderValue::from_str(&value).unwrap());
-            let _ = db.update_last_login(data.email).await;
-            (
-                StatusCode::OK,
-                headers,
-                Json(json!({
-                    "success":true,
-                    "token":t,
-                    "verified":u.verified
-                })),
-            )
+            match tokens {
+                Ok((t, headers)) => {
+                    let _ = db.update_last_login(data.email).await;
+                    (
+                        StatusCode::OK,
+                        headers,
+                        Json(json!({
+                            "success":true,
+                            "token":t,
+                            "verified":u.verified
+                        })),
+                    )
+                }
+                Err(e) => {
+                    error!("{}", e);
+                    (
+                        StatusCode::INTERNAL_SERVER_ERROR,
+                        HeaderMap::new(),
+                        Json(json!({
+                            "success":false,
+                            "err":"unable to create session"
+                        })),
+                    )
+                }
+            }
         }
         None => (
             StatusCode::NOT_ACCEPTABLE,
@@ -177,47 +247,119 @@ pub async fn login(
     }
 }
 
-pub async fn logout(Extension(db): Extension<Arc<Db>>, req: Request<Body>) -> impl IntoResponse {
-    let (mut parts, _) = req.into_parts();
-    let res = extract_cookie_into_user(&parts, &db).await;
+pub async fn refresh(
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Extension(db): Extension<Arc<Db>>,
+    req: Request<Body>,
+) -> impl IntoResponse {
+    let (parts, _) = req.into_parts();
+    let claims = match find_cookie(&parts.headers, REFRESH_COOKIE) {
+        Some(token) => validate_refresh_jwt(&token),
+        None => Err(String::from("refresh cookie not found")),
+    };
+    let claims = match claims {
+        Ok(c) => c,
+        Err(e) => {
+            error!("{}", e);
+            return (
+                StatusCode::UNAUTHORIZED,
+                clear_session_cookies(),
+                Json(json!({
+                    "success":false,
+                    "err":"please login"
+                })),
+            );
+        }
+    };
+    let jti = new_token_id();
+    let device = device_info(&parts.headers, addr);
+    let res = db
+        .rotate_session(claims.sid.clone(), &claims.jti, &jti, device, REFRESH_GRACE)
+        .await;
     match res {
-        Ok(Some(mut user)) => {
+        Ok(Some(jti)) => match issue_tokens(&claims.sub, &claims.sid, &jti) {
+            Ok((t, headers)) => (
+                StatusCode::OK,
+                headers,
+                Json(json!({
+                    "success":true,
+                    "token":t
+                })),
+            ),
+            Err(e) => {
+                error!("{}", e);
+                (
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    HeaderMap::new(),
+                    Json(json!({
+                        "success":false,
+                        "err":"unable to refresh session"
+                    })),
+                )
+            }
+        },
+        Ok(None) => {
+            // Either the session is gone or a token rotated away longer than REFRESH_GRACE ago came back,
+            // in which case somebody else holds a copy, so kill the whole family
+            warn!("refresh token reuse or dead session : {}", claims.sid);
+            if let Err(e) = db.revoke_session(claims.sid).await {
+                error!("{}", e);
+            }
+            (
+                StatusCode::UNAUTHORIZED,
+                clear_session_cookies(),
+                Json(json!({
+                    "success":false,
+                    "err":"session revoked, please login again"
+                })),
+            )
+        }
+        Err(e) => {
+            error!("{}", e);
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                HeaderMap::new(),
+                Json(json!({
+                    "success":false,
+                    "err":"unable to refresh session"
+                })),
+            )
+        }
+    }
+}
+
+pub async fn logout(
+    Extension(db): Extension<Arc<Db>>,
+    Extension(manager): Extension<Arc<Mutex<Manager>>>,
+    req: Request<Body>,
+) -> impl IntoResponse {
+    let (mut parts, _) = req.into_parts();
+    // An expired access token should not stop anyone from logging out
+    let session = match extract_cookie(parts.clone(), &db).await {
+        Ok(c) => Some((c.sub, c.sid)),
+        Err(_) => find_cookie(&parts.headers, REFRESH_COOKIE)
+            .and_then(|t| validate_refresh_jwt(&t).ok())
+            .map(|c| (c.sub, c.sid)),
+    };
+    match session {
+        Some((user_id, session_id)) => {
+            if let Err(e) = db.revoke_session(session_id.clone()).await {
+                error!("{}", e);
+            }
+            manager.lock().await.close_session(&session_id);
             let res = parts.headers.remove("Cookie");
             info!("deleted {:?}", res);
-            let http = match env::var("ENV").unwrap().as_str() {
-                "development" => {
-                    true
-                }
-
-                "production" => {
-                    false
-                }
-                
-                _ => {
-                    debug!("no variable named ENV found deafaulting to development");
-                    true
-                }
-            };
-            let cookie = CookieBuilder::build(
-                Cookie::build(("jwt", ""))
-                    .path("/")
-                    .max_age(Samay::ZERO)
-                    .http_only(http)
-                    .same_site(cookie::SameSite::None)
-                    .secure(true),
-            );
-            let mut header = HeaderMap::new();
-            header.insert(header::SET_COOKIE, cookie.to_string().parse().unwrap());
+            let user = db.find_user_with_id(user_id).await.map(|mut u| u.hide_pass());
             (
                 StatusCode::OK,
-                header,
+                clear_session_cookies(),
                 Json(json!({
                     "logout":"success",
-                    "user": user.hide_pass()
+                    "user": user
                 })),
             )
         }
-        _ => {
+        None => {
             error!("cannot logout, error in auth : logout");
             (StatusCode::BAD_REQUEST, HeaderMap::new(), Json(json!({})))
         }
@@ -242,3 +384,134 @@ pub async fn session(Extension(db): Extension<Arc<Db>>, req: Request<Body>) -> i
         ),
     }
 }
+
+pub async fn list_sessions(
+    Extension(db): Extension<Arc<Db>>,
+    req: Request<Body>,
+) -> impl IntoResponse {
+    let (parts, _) = req.into_parts();
+    let claims = match extract_cookie(parts, &db).await {
+        Ok(c) => c,
+        Err(e) => {
+            return (
+                StatusCode::UNAUTHORIZED,
+                Json(json!({
+                    "err":e
+                })),
+            )
+        }
+    };
+    let res = db.get_active_sessions(claims.sub).await;
+    match res {
+        Ok(sessions) => {
+            let sessions: Vec<SessionInfo> = sessions.iter().map(|s| s.info(&claims.sid)).collect();
+            (
+                StatusCode::OK,
+                Json(json!({
+                    "sessions":sessions
+                })),
+            )
+        }
+        Err(e) => {
+            error!("{}", e);
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({
+                    "err":e.error()
+                })),
+            )
+        }
+    }
+}
+
+pub async fn revoke_session(
+    Extension(db): Extension<Arc<Db>>,
+    Extension(manager): Extension<Arc<Mutex<Manager>>>,
+    Path(session_id): Path<ObjectId>,
+    req: Request<Body>,
+) -> impl IntoResponse {
+    let (parts, _) = req.into_parts();
+    let claims = match extract_cookie(parts, &db).await {
+        Ok(c) => c,
+        Err(e) => {
+            return (
+                StatusCode::UNAUTHORIZED,
+                Json(json!({
+                    "err":e
+                })),
+            )
+        }
+    };
+    let res = db.revoke_user_session(claims.sub, session_id).await;
+    match res {
+        Ok(true) => {
+            manager.lock().await.close_session(&session_id.to_hex());
+            (
+                StatusCode::OK,
+                Json(json!({
+                    "success":true
+                })),
+            )
+        }
+        Ok(false) => (
+            StatusCode::NOT_FOUND,
+            Json(json!({
+                "err":"session not found"
+            })),
+        ),
+        Err(e) => {
+            error!("{}", e);
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({
+                    "err":e.error()
+                })),
+            )
+        }
+    }
+}
+
+// Log out everywhere, the current session included
+pub async fn revoke_all_sessions(
+    Extension(db): Extension<Arc<Db>>,
+    Extension(manager): Extension<Arc<Mutex<Manager>>>,
+    req: Request<Body>,
+) -> impl IntoResponse {
+    let (parts, _) = req.into_parts();
+    let claims = match extract_cookie(parts, &db).await {
+        Ok(c) => c,
+        Err(e) => {
+            return (
+                StatusCode::UNAUTHORIZED,
+                HeaderMap::new(),
+                Json(json!({
+                    "err":e
+                })),
+            )
+        }
+    };
+    let res = db.revoke_all_sessions(claims.sub.clone()).await;
+    match res {
+        Ok(count) => {
+            manager.lock().await.close_user(&claims.sub);
+            (
+                StatusCode::OK,
+                clear_session_cookies(),
+                Json(json!({
+                    "success":true,
+                    "revoked":count
+                })),
+            )
+        }
+        Err(e) => {
+            error!("{}", e);
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                HeaderMap::new(),
+                Json(json!({
+                    "err":e.error()
+                })),
+            )
+        }
+    }
+}
@@ -1,49 +1,321 @@
 use axum::{
     body::Body,
     extract::{
-        ws::{Message, WebSocket},
+        ws::{close_code, CloseFrame, Message, WebSocket},
         WebSocketUpgrade,
     },
     http::Request,
     response::IntoResponse,
     Extension,
 };
-use bson::DateTime;
-use futures::{stream::SplitSink, SinkExt, StreamExt};
+use bson::{doc, oid::ObjectId, DateTime};
+use futures::{future::join_all, SinkExt, StreamExt};
 use log::{debug, error, info};
-use mongodb::results::InsertOneResult;
-use serde_json::{from_str, json, to_string};
-use std::{collections::HashMap, sync::Arc};
-use tokio::sync::{mpsc, Mutex};
+use serde_json::{from_str, from_value, to_string, Value};
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+    time::Duration,
+};
+use tokio::{
+    sync::{mpsc, Mutex, Notify},
+    time::{self, sleep_until, Instant},
+};
+
+use crate::{
+    authz,
+    db::{Db, IntoObjectId},
+    models::{
+        Ack, Change, ChangeEvent, ChatMessage, Claims, ClientFrame, ClientOp, ConversationRef,
+        DeleteScope, ErrorCode, FrameError, Group, GroupMessage, GroupPermission, MessageDelete,
+        MessageEdit, MessageRef, MyError, Presence, PresenceStatus, Reaction, ReactionChange,
+        Receipt, ServerEvent, ServerFrame, ServerOp, SyncRequest, SystemMessage, ThreadRef,
+        MAX_SYNC_CHANGES, PROTOCOL_VERSION,
+    },
+    utils::{delete_window, edit_window, extract_cookie_for_ws, idle_timeout, ping_interval},
+};
+pub enum Outbound {
+    Frame(ServerFrame),
+    Close(String),
+}
 
-use crate::{db::{Db, IntoObjectId}, models::ChatMessage, utils::extract_cookie_for_ws};
 #[derive(Clone)]
 pub struct Client {
-    sender: mpsc::UnboundedSender<ChatMessage>,
-    _active: bool,
+    sender: mpsc::UnboundedSender<Outbound>,
+    session_id: String,
+    // the last time this socket sent a frame
+    last_active: Instant,
 }
 
+// How long a closing socket gets to flush before its writer is dropped
+const CLOSE_GRACE: Duration = Duration::from_secs(5);
+
+// A user whose sockets all stayed quiet this long shows as away
+const AWAY_AFTER: Duration = Duration::from_secs(5 * 60);
+
+// A repeated "typing started" inside this window only keeps the indicator alive
+const TYPING_THROTTLE: Duration = Duration::from_secs(3);
+// An indicator nobody refreshed for this long is stopped, in case the client vanished
+const TYPING_TIMEOUT: Duration = Duration::from_secs(8);
+
+struct Typing {
+    recipients: Vec<ObjectId>,
+    last_sent: Instant,
+    expires: Instant,
+}
+
+// Every user can have several sockets open (tabs, phone, ...), each one gets its own connection id
 pub struct Manager {
-    clients: HashMap<String, Client>,
+    clients: HashMap<String, HashMap<u64, Client>>,
+    // thread root -> the (user, connection) pairs that have the thread open
+    threads: HashMap<ObjectId, HashSet<(String, u64)>>,
+    typing: HashMap<(ConversationRef, ObjectId), Typing>,
+    // what each user's friends were last told, users who are offline are not in here
+    presence: HashMap<String, Presence>,
+    next_conn: u64,
 }
 
 impl Manager {
     pub fn new() -> Manager {
         Manager {
             clients: HashMap::new(),
+            threads: HashMap::new(),
+            typing: HashMap::new(),
+            presence: HashMap::new(),
+            next_conn: 0,
+        }
+    }
+
+    pub fn insert(&mut self, key: String, value: Client) -> u64 {
+        self.next_conn += 1;
+        let conn = self.next_conn;
+        self.clients.entry(key).or_default().insert(conn, value);
+        conn
+    }
+
+    pub fn remove(&mut self, c: &str, conn: u64) {
+        if let Some(conns) = self.clients.get_mut(c) {
+            conns.remove(&conn);
+            if conns.is_empty() {
+                self.clients.remove(c);
+            }
+        }
+        self.threads.retain(|_, subs| {
+            subs.retain(|(_, sub)| *sub != conn);
+            !subs.is_empty()
+        });
+        // the last socket of the user is gone, nobody is typing there any more
+        if !self.clients.contains_key(c) {
+            let typing: Vec<_> = self
+                .typing
+                .keys()
+                .filter(|(_, user)| user.to_hex() == c)
+                .copied()
+                .collect();
+            for (target, user) in typing {
+                self.stop_typing(target, user);
+            }
+        }
+    }
+
+    /// Marks the connection as active, returns true when that brings the user back from away
+    pub fn touch(&mut self, c: &str, conn: u64) -> bool {
+        let was_away = self.presence.get(c) == Some(&Presence::Away);
+        if let Some(client) = self
+            .clients
+            .get_mut(c)
+            .and_then(|conns| conns.get_mut(&conn))
+        {
+            client.last_active = Instant::now();
+        }
+        was_away
+    }
+
+    pub fn presence_of(&self, c: &str) -> Presence {
+        match self.clients.get(c) {
+            Some(conns) if conns.values().any(|c| c.last_active.elapsed() < AWAY_AFTER) => {
+                Presence::Online
+            }
+            Some(_) => Presence::Away,
+            None => Presence::Offline,
+        }
+    }
+
+    // The user's presence if it is not what their friends were last told, which it then becomes
+    fn presence_changed(&mut self, c: &str) -> Option<Presence> {
+        let presence = self.presence_of(c);
+        let told = self.presence.get(c).copied().unwrap_or(Presence::Offline);
+        if presence == told {
+            return None;
+        }
+        if presence == Presence::Offline {
+            self.presence.remove(c);
+        } else {
+            self.presence.insert(c.to_string(), presence);
+        }
+        Some(presence)
+    }
+
+    // Everyone who is not offline, for the sweep that notices users going away
+    pub fn present_users(&self) -> Vec<String> {
+        self.presence.keys().cloned().collect()
+    }
+
+    // True when the user already said they are typing a moment ago, that only pushes the expiry back
+    fn typing_throttled(&mut self, target: ConversationRef, user: ObjectId) -> bool {
+        match self.typing.get_mut(&(target, user)) {
+            Some(t) if t.last_sent.elapsed() < TYPING_THROTTLE => {
+                t.expires = Instant::now() + TYPING_TIMEOUT;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Tells `recipients` the user is typing. Returns when it expires, or None when
+    /// an indicator was already running and has an expiry task watching it.
+    fn start_typing(
+        &mut self,
+        target: ConversationRef,
+        user: ObjectId,
+        recipients: Vec<ObjectId>,
+    ) -> Option<Instant> {
+        let now = Instant::now();
+        let frame = ServerFrame::event(ServerEvent::TypingStarted {
+            target,
+            user_id: user,
+        });
+        self.send_to_users(recipients.iter().filter(|r| **r != user), &frame, None);
+        let expires = now + TYPING_TIMEOUT;
+        let previous = self.typing.insert(
+            (target, user),
+            Typing {
+                recipients,
+                last_sent: now,
+                expires,
+            },
+        );
+        match previous {
+            Some(_) => None,
+            None => Some(expires),
+        }
+    }
+
+    fn stop_typing(&mut self, target: ConversationRef, user: ObjectId) {
+        if let Some(t) = self.typing.remove(&(target, user)) {
+            let frame = ServerFrame::event(ServerEvent::TypingStopped {
+                target,
+                user_id: user,
+            });
+            self.send_to_users(t.recipients.iter().filter(|r| **r != user), &frame, None);
+        }
+    }
+
+    // Stops the indicator if it ran out, otherwise returns when to look again
+    fn expire_typing(&mut self, target: ConversationRef, user: ObjectId) -> Option<Instant> {
+        let expires = self.typing.get(&(target, user))?.expires;
+        if expires > Instant::now() {
+            return Some(expires);
+        }
+        self.stop_typing(target, user);
+        None
+    }
+
+    pub fn subscribe_thread(&mut self, thread_id: ObjectId, user: &str, conn: u64) {
+        self.threads
+            .entry(thread_id)
+            .or_default()
+            .insert((user.to_string(), conn));
+    }
+
+    pub fn unsubscribe_thread(&mut self, thread_id: ObjectId, user: &str, conn: u64) {
+        if let Some(subs) = self.threads.get_mut(&thread_id) {
+            subs.remove(&(user.to_string(), conn));
+            if subs.is_empty() {
+                self.threads.remove(&thread_id);
+            }
+        }
+    }
+
+    /// Sends the frame to the connections subscribed to the thread whose user is still in `members`
+    pub fn send_to_thread(
+        &self,
+        thread_id: ObjectId,
+        members: &HashSet<ObjectId>,
+        frame: &ServerFrame,
+        skip: Option<u64>,
+    ) {
+        let Some(subs) = self.threads.get(&thread_id) else {
+            return;
+        };
+        for (user, conn) in subs {
+            if Some(*conn) == skip || !ObjectId::parse_str(user).is_ok_and(|u| members.contains(&u))
+            {
+                continue;
+            }
+            if let Some(client) = self.clients.get(user).and_then(|c| c.get(conn)) {
+                let _ = client.sender.send(Outbound::Frame(frame.clone()));
+            }
+        }
+    }
+
+    /// Sends the frame to every connection of the user except `skip`,
+    /// returns how many connections it was handed to
+    pub fn send_to_user(&self, c: &str, frame: &ServerFrame, skip: Option<u64>) -> usize {
+        let mut sent = 0;
+        if let Some(conns) = self.clients.get(c) {
+            for (conn, client) in conns {
+                if Some(*conn) == skip {
+                    continue;
+                }
+                if client.sender.send(Outbound::Frame(frame.clone())).is_ok() {
+                    sent += 1;
+                }
+            }
         }
+        sent
     }
 
-    pub fn insert(&mut self, key: String, value: Client) {
-        self.clients.insert(key, value);
+    pub fn send_to_conn(&self, c: &str, conn: u64, frame: &ServerFrame) -> bool {
+        match self.clients.get(c).and_then(|conns| conns.get(&conn)) {
+            Some(client) => client.sender.send(Outbound::Frame(frame.clone())).is_ok(),
+            None => false,
+        }
     }
 
-    pub fn remove(&mut self, c: String) {
-        self.clients.remove(&c);
+    /// Returns the users the frame reached on at least one connection
+    pub fn send_to_users<'a>(
+        &self,
+        users: impl IntoIterator<Item = &'a ObjectId>,
+        frame: &ServerFrame,
+        skip: Option<u64>,
+    ) -> Vec<ObjectId> {
+        users
+            .into_iter()
+            .filter(|user| self.send_to_user(&user.to_hex(), frame, skip) > 0)
+            .copied()
+            .collect()
     }
 
-    pub fn find(&self, c: &String) -> Option<&Client> {
-        self.clients.get(c)
+    /// Tells every socket opened with this session to shut down
+    pub fn close_session(&self, session_id: &str) {
+        for client in self.clients.values().flat_map(|conns| conns.values()) {
+            if client.session_id == session_id {
+                let _ = client
+                    .sender
+                    .send(Outbound::Close(String::from("session revoked")));
+            }
+        }
+    }
+
+    pub fn close_user(&self, user_id: &str) {
+        if let Some(conns) = self.clients.get(user_id) {
+            for client in conns.values() {
+                let _ = client
+                    .sender
+                    .send(Outbound::Close(String::from("session revoked")));
+            }
+        }
     }
 }
 
@@ -54,162 +326,1359 @@ pub async fn handle_websocket(
     req: Request<Body>,
 ) -> impl IntoResponse {
     ws.on_failed_upgrade(|err: axum::Error| {
-        error!("error :{}", err.to_string());
+        error!("error :{}", err);
     })
     .on_upgrade(|ws| async move {
         let (parts, _) = req.into_parts();
-        let cookie = extract_cookie_for_ws(parts).await;
-        match cookie {
-            Some(id) => {
-                info!("{}", id);
-                handle_chat(manager.clone(), id, ws, db).await;
-            }
-            None => (),
+        let cookie = extract_cookie_for_ws(parts, &db).await;
+        if let Some(claims) = cookie {
+            info!("{}", claims.sub);
+            db.touch_session(claims.sid.clone()).await;
+            handle_chat(manager.clone(), claims, ws, db).await;
         }
     })
 }
 
-async fn handle_chat(manager: Arc<Mutex<Manager>>, id: String, ws: WebSocket, db: Arc<Db>) {
+async fn handle_chat(manager: Arc<Mutex<Manager>>, claims: Claims, ws: WebSocket, db: Arc<Db>) {
     debug!("Websocket connection established");
+    let id = claims.sub;
     // ========== Splitting socket ==========
-    let (s, mut receiver) = ws.split();
-    // For sharing sender concurrently ==========
-    let sender = Arc::new(Mutex::new(s));
+    let (mut sender, mut receiver) = ws.split();
     // ========== Local channels to transfer data among different threads
-    let (tx, mut rx) = mpsc::unbounded_channel::<ChatMessage>();
+    let (tx, mut rx) = mpsc::unbounded_channel::<Outbound>();
+    // ========== Lets the write loop stop the read loop once the socket is done ==========
+    let closed = Arc::new(Notify::new());
+    let closed_rx = Arc::clone(&closed);
     // ========== Adding client to the map ========== ==========
     let c = Client {
-        sender: tx,
-        _active: true,
-    };
-    manager.lock().await.insert(id.clone(), c);
-    // Cloning DB
-    let db_rx = Arc::clone(&db);
-    let manager_rx = Arc::clone(&manager);
-    let sender_rx = Arc::clone(&sender);
-    // ========== Readloop ==========
-
-    let readloop = tokio::spawn(async move {
-        while let Some(msg) = rx.recv().await {
-            let mut sender = sender.lock().await;
-            sender
-                .send(Message::text(to_string(&msg).unwrap()))
-                .await
-                .unwrap();
+        sender: tx.clone(),
+        session_id: claims.sid,
+        last_active: Instant::now(),
+    };
+    let conn = manager.lock().await.insert(id.clone(), c);
+    refresh_presence(&db, &manager, &id).await;
+    // ========== Write loop ==========
+    // Writes everything queued for this socket and pings it. A failed send only ends this socket.
+    let writer_id = id.clone();
+    let mut writer = tokio::spawn(async move {
+        let period = ping_interval();
+        let mut ping = time::interval_at(Instant::now() + period, period);
+        loop {
+            let msg = tokio::select! {
+                out = rx.recv() => match out {
+                    Some(Outbound::Frame(frame)) => match to_string(&frame) {
+                        Ok(text) => Message::text(text),
+                        Err(e) => {
+                            error!("failed to serialize a frame for {} : {}", writer_id, e);
+                            continue;
+                        }
+                    },
+                    Some(Outbound::Close(reason)) => {
+                        let frame = CloseFrame {
+                            code: close_code::POLICY,
+                            reason: reason.into(),
+                        };
+                        let _ = sender.send(Message::Close(Some(frame))).await;
+                        break;
+                    }
+                    None => break,
+                },
+                _ = ping.tick() => Message::Ping(Default::default()),
+            };
+            if let Err(e) = sender.send(msg).await {
+                error!("failed to send to {} : {}", writer_id, e);
+                break;
+            }
         }
+        // whatever stopped the writes, the socket is done
+        closed.notify_one();
     });
 
-    // ========== Write loop ==========
+    // ========== Read loop ==========
+    let connection = Connection {
+        user_id: id.clone(),
+        conn,
+        db,
+        manager: Arc::clone(&manager),
+    };
     tokio::spawn(async move {
+        let idle = idle_timeout();
+        let mut deadline = Instant::now() + idle;
         loop {
-            match receiver.next().await {
-                Some(Ok(msg)) => {
-                    if let Ok(data) = msg.to_text() {
-                        if let Ok(message) = from_str::<ChatMessage>(data) {
-                            match message {
-                                ChatMessage::Direct(mut m) => {
-                                    m.created_at = Some(DateTime::now());
-                                    if !db.chat_exists(m.chat_id.clone().unwrap()).await {
-                                        error!("Chat does not exists");
-                                        return;
-                                    }
-                                    let client = {
-                                        let mgr = manager_rx.lock().await;
-                                        mgr.find(&m.to_id.clone().unwrap().to_hex()).cloned()
-                                    };
-                                    m.from_id = Some(id.clone().into_object_id());
-                                    let sender = sender_rx.clone();
-                                    match client {
-                                        Some(c) => {
-                                            debug!("user found");
-                                            c.sender.send(ChatMessage::Direct(m.clone())).unwrap();
-                                            let result = db_rx
-                                                .add_message_to_db(ChatMessage::Direct(m.clone()))
-                                                .await;
-                                            match_result(sender, result).await;
-                                        }
-                                        None => {
-                                            let result = db_rx
-                                                .add_message_to_db(ChatMessage::Direct(m.clone()))
-                                                .await;
-                                            match_result(sender, result).await;
-                                        }
-                                    }
-                                }
-                                ChatMessage::Group(mut m) => {
-                                    m.created_at = Some(DateTime::now());
-                                    if !db.group_exists(m.group_id.clone().unwrap()).await {
-                                        error!("Group does not exists");
-                                        return;
-                                    }
-                                    m.from_id = Some(id.clone().into_object_id());
-                                    let group =
-                                        db_rx.find_group(m.group_id.unwrap()).await.unwrap();
-                                    let mgr = manager_rx.lock().await;
-                                    for member in group.members {
-                                        let sender = sender_rx.clone();
-                                        let user = mgr.find(&member.to_hex());
-                                        match user {
-                                            Some(u) => {
-                                                #[allow(warnings)]
-                                                u.sender.send(ChatMessage::Group(m.clone()));
-                                                let result = db_rx
-                                                    .add_message_to_db(ChatMessage::Group(
-                                                        m.clone(),
-                                                    ))
-                                                    .await;
-                                                match_result(sender, result).await;
-                                            }
-                                            None => {
-                                                let result = db_rx
-                                                    .add_message_to_db(ChatMessage::Group(
-                                                        m.clone(),
-                                                    ))
-                                                    .await;
-                                                match_result(sender, result).await;
-                                            }
-                                        }
-                                    }
-                                }
-                            };
-                        } else {
-                            error!("kuch dikkat hai");
-                        }
-                    } else {
-                        error!("kuch aur dikkat hai");
+            let next = tokio::select! {
+                next = receiver.next() => next,
+                _ = closed_rx.notified() => None,
+                _ = sleep_until(deadline) => {
+                    info!("nothing from {} in {:?}, closing", id, idle);
+                    let _ = tx.send(Outbound::Close(String::from("idle timeout")));
+                    None
+                }
+            };
+            deadline = Instant::now() + idle;
+            match next {
+                Some(Ok(Message::Text(data))) => {
+                    if manager.lock().await.touch(&id, conn) {
+                        refresh_presence(&connection.db, &manager, &id).await;
                     }
+                    let reply = connection.handle_text(data.as_str()).await;
+                    let _ = tx.send(Outbound::Frame(reply));
                 }
+                Some(Ok(Message::Binary(_))) => {
+                    let err =
+                        FrameError::new(ErrorCode::BadFrame, "only text frames are supported");
+                    let _ = tx.send(Outbound::Frame(ServerFrame::error(None, err)));
+                }
+                // pongs and pings only show the socket is still there
+                Some(Ok(_)) => (),
                 Some(Err(e)) => {
-                    error!("{}", e.to_string());
+                    error!("{}", e);
+                    break;
+                }
+                None => break,
+            }
+        }
+        info!("Shutting down read loop for {}", id);
+        manager.lock().await.remove(&id, conn);
+        refresh_presence(&connection.db, &manager, &id).await;
+        // with every sender gone the writer flushes what is queued, like the close frame, and
+        // stops. One stuck on a half-open connection is not waited for.
+        drop(tx);
+        if time::timeout(CLOSE_GRACE, &mut writer).await.is_err() {
+            writer.abort();
+        }
+    });
+}
+
+// Everything a single socket needs to act on behalf of its user
+struct Connection {
+    user_id: String,
+    conn: u64,
+    db: Arc<Db>,
+    manager: Arc<Mutex<Manager>>,
+}
+
+impl Connection {
+    /// Handles one incoming text frame and returns the ack or error frame for it.
+    /// Nothing in here is allowed to end the connection.
+    async fn handle_text(&self, data: &str) -> ServerFrame {
+        let value = match from_str::<Value>(data) {
+            Ok(v) => v,
+            Err(e) => {
+                return ServerFrame::error(None, FrameError::new(ErrorCode::BadFrame, e));
+            }
+        };
+        let frame_id = value.get("id").and_then(Value::as_str).map(String::from);
+        if value.get("v").and_then(Value::as_u64) != Some(PROTOCOL_VERSION as u64) {
+            let err = FrameError::new(
+                ErrorCode::UnsupportedVersion,
+                format!("expected protocol version {}", PROTOCOL_VERSION),
+            );
+            return ServerFrame::error(frame_id, err);
+        }
+        let frame = match from_value::<ClientFrame>(value) {
+            Ok(f) => f,
+            Err(e) => {
+                return ServerFrame::error(frame_id, FrameError::new(ErrorCode::InvalidPayload, e));
+            }
+        };
+        let res = match frame.op {
+            ClientOp::Send(msg) => send_chat_message(
+                &self.db,
+                &self.manager,
+                &self.user_id,
+                Some(self.conn),
+                *msg,
+            )
+            .await
+            .map(ServerOp::Ack),
+            // the event goes to this connection too, the ack only says it was applied
+            ClientOp::Edit(edit) => {
+                let id = edit.target.message_id();
+                edit_chat_message(&self.db, &self.manager, &self.user_id, None, edit)
+                    .await
+                    .map(|_| ServerOp::Ack(Ack::applied(id)))
+            }
+            ClientOp::Delete(delete) => {
+                let id = delete.target.message_id();
+                delete_chat_message(&self.db, &self.manager, &self.user_id, None, delete)
+                    .await
+                    .map(|_| ServerOp::Ack(Ack::applied(id)))
+            }
+            ClientOp::React(change) => {
+                let id = change.target.message_id();
+                react_to_message(&self.db, &self.manager, &self.user_id, None, change, true)
+                    .await
+                    .map(|_| ServerOp::Ack(Ack::applied(id)))
+            }
+            ClientOp::Unreact(change) => {
+                let id = change.target.message_id();
+                react_to_message(&self.db, &self.manager, &self.user_id, None, change, false)
+                    .await
+                    .map(|_| ServerOp::Ack(Ack::applied(id)))
+            }
+            ClientOp::SubscribeThread(thread) => subscribe_thread(
+                &self.db,
+                &self.manager,
+                &self.user_id,
+                self.conn,
+                thread,
+                true,
+            )
+            .await
+            .map(|_| ServerOp::Ack(Ack::applied(thread.thread_id))),
+            ClientOp::UnsubscribeThread(thread) => subscribe_thread(
+                &self.db,
+                &self.manager,
+                &self.user_id,
+                self.conn,
+                thread,
+                false,
+            )
+            .await
+            .map(|_| ServerOp::Ack(Ack::applied(thread.thread_id))),
+            ClientOp::MarkRead(target) => {
+                mark_read(&self.db, &self.manager, &self.user_id, None, target)
+                    .await
+                    .map(|_| ServerOp::Ack(Ack::applied(target.message_id())))
+            }
+            // the frame already counted as activity, any presence change went out to the friends
+            ClientOp::Heartbeat => Ok(ServerOp::Ack(Ack::received())),
+            ClientOp::Sync(req) => {
+                sync_changes(&self.db, &self.manager, &self.user_id, self.conn, req)
+                    .await
+                    .map(|_| ServerOp::Ack(Ack::received()))
+            }
+            ClientOp::TypingStarted(target) => {
+                set_typing(&self.db, &self.manager, &self.user_id, target, true)
+                    .await
+                    .map(|_| ServerOp::Ack(Ack::received()))
+            }
+            ClientOp::TypingStopped(target) => {
+                set_typing(&self.db, &self.manager, &self.user_id, target, false)
+                    .await
+                    .map(|_| ServerOp::Ack(Ack::received()))
+            }
+        };
+        match res {
+            Ok(op) => ServerFrame::new(Some(frame.id), op),
+            Err(e) => ServerFrame::error(Some(frame.id), e),
+        }
+    }
+}
+
+/// Stores a message sent by `user_id` and fans it out to everyone who should see it.
+/// `origin` is the connection it came from, which gets the ack instead of a copy.
+/// A retried send (same `client_msg_id`) is acked with the original and not delivered twice.
+pub async fn send_chat_message(
+    db: &Db,
+    manager: &Mutex<Manager>,
+    user_id: &str,
+    origin: Option<u64>,
+    msg: ChatMessage,
+) -> Result<Ack, FrameError> {
+    match msg {
+        ChatMessage::Direct(mut m) => {
+            let chat_id = match m.chat_id {
+                Some(chat_id) => chat_id,
+                None => {
+                    return Err(FrameError::new(
+                        ErrorCode::InvalidPayload,
+                        "chat_id is required",
+                    ))
                 }
+            };
+            let chat = authz::chat_participant(db, user_id, chat_id).await?;
+            let from_id = user_id.to_string().into_object_id();
+            // the recipient comes from the chat, whatever the client put in to_id
+            let to_id = match chat.other_user(from_id) {
+                Some(to_id) => to_id,
                 None => {
-                    info!("Shutting down readloop for {}", id);
-                    manager_rx.lock().await.remove(id);
-                    readloop.abort();
-                    break;
+                    return Err(FrameError::new(
+                        ErrorCode::InvalidPayload,
+                        "chat has no other participant",
+                    ))
+                }
+            };
+            if let Some(reply_to) = m.reply_to {
+                let quoted = db.find_message(reply_to).await;
+                if quoted.is_none_or(|q| q.chat_id != Some(chat_id)) {
+                    return Err(FrameError::new(
+                        ErrorCode::InvalidPayload,
+                        "reply_to must be a message in the same chat",
+                    ));
+                }
+            }
+            m.to_id = Some(to_id);
+            m.clear_server_fields();
+            m.created_at = Some(DateTime::now());
+            m.from_id = Some(from_id);
+            let (stored, message_id, ack) = store_message(db, ChatMessage::Direct(m)).await?;
+            if ack.duplicate {
+                return Ok(ack);
+            }
+            let conversation = ConversationRef::Direct { chat_id };
+            let change = ChangeEvent::Message {
+                message: stored.clone(),
+            };
+            log_change(db, conversation, None, change).await;
+            let frame = ServerFrame::message(stored);
+            let delivered = {
+                let mgr = manager.lock().await;
+                let delivered = mgr.send_to_user(&to_id.to_hex(), &frame, None);
+                debug!("delivered to {} connections", delivered);
+                // keep the sender's other devices in sync as well
+                mgr.send_to_user(user_id, &frame, origin);
+                delivered
+            };
+            if delivered > 0 {
+                let target = MessageRef::Direct {
+                    chat_id,
+                    message_id,
+                };
+                record_delivery(db, manager, target, from_id, &[to_id]).await;
+                push_unread_counts(db, manager, target, &[to_id]).await;
+            }
+            Ok(ack)
+        }
+        ChatMessage::Group(mut m) => {
+            let group_id = match m.group_id {
+                Some(group_id) => group_id,
+                None => {
+                    return Err(FrameError::new(
+                        ErrorCode::InvalidPayload,
+                        "group_id is required",
+                    ))
                 }
+            };
+            let group = authz::group_permission(
+                db,
+                user_id,
+                group_id,
+                GroupPermission::Post,
+                "only admins can post in this group",
+            )
+            .await?;
+            let sender = user_id.to_string().into_object_id();
+            if let Some(until) = group.muted_until(sender) {
+                return Err(FrameError::new(
+                    ErrorCode::Forbidden,
+                    format!(
+                        "you are muted in this group until {}",
+                        until.try_to_rfc3339_string().unwrap_or_default()
+                    ),
+                ));
+            }
+            if let Some(reply_to) = m.reply_to {
+                let quoted = db.find_group_message(reply_to).await;
+                if quoted.is_none_or(|q| q.group_id != Some(group_id)) {
+                    return Err(FrameError::new(
+                        ErrorCode::InvalidPayload,
+                        "reply_to must be a message in the same group",
+                    ));
+                }
+            }
+            let thread = m.thread_id.map(|thread_id| ThreadRef {
+                group_id,
+                thread_id,
+            });
+            if let Some(thread) = thread {
+                thread_root(db, thread).await?;
+            }
+            m.clear_server_fields();
+            m.created_at = Some(DateTime::now());
+            m.from_id = Some(user_id.to_string().into_object_id());
+            let (stored, message_id, ack) = store_message(db, ChatMessage::Group(m)).await?;
+            if ack.duplicate {
+                return Ok(ack);
+            }
+            let conversation = ConversationRef::Group { group_id };
+            let change = ChangeEvent::Message {
+                message: stored.clone(),
+            };
+            log_change(db, conversation, None, change).await;
+            let frame = ServerFrame::message(stored);
+            // receipts are only kept for the group timeline, not for thread replies
+            let Some(thread) = thread else {
+                let mut delivered =
+                    manager
+                        .lock()
+                        .await
+                        .send_to_users(&group.members, &frame, origin);
+                delivered.retain(|u| *u != sender);
+                let target = MessageRef::Group {
+                    group_id,
+                    message_id,
+                };
+                record_delivery(db, manager, target, sender, &delivered).await;
+                push_unread_counts(db, manager, target, &delivered).await;
+                return Ok(ack);
+            };
+            // replies go to whoever has the thread open, everyone else only sees the count move
+            let root = thread_root(db, thread).await?;
+            let update = ServerFrame::event(ServerEvent::ThreadUpdated {
+                message: Box::new(ChatMessage::Group(root).for_clients()),
+            });
+            let mgr = manager.lock().await;
+            mgr.send_to_thread(thread.thread_id, &group.members, &frame, origin);
+            mgr.send_to_users(&group.members, &update, None);
+            Ok(ack)
+        }
+        ChatMessage::System(_) => Err(FrameError::new(
+            ErrorCode::InvalidPayload,
+            "system messages are written by the server",
+        )),
+    }
+}
+
+/// Edits a message `user_id` sent, if it is still inside the edit window, and pushes the new
+/// version to everyone in the conversation except the `origin` connection.
+pub async fn edit_chat_message(
+    db: &Db,
+    manager: &Mutex<Manager>,
+    user_id: &str,
+    origin: Option<u64>,
+    edit: MessageEdit,
+) -> Result<ChatMessage, FrameError> {
+    if edit.content.trim().is_empty() {
+        return Err(FrameError::new(
+            ErrorCode::InvalidPayload,
+            "content cannot be empty",
+        ));
+    }
+    let editor = user_id.to_string().into_object_id();
+    let target = resolve_target(db, user_id, edit.target).await?;
+    if target
+        .group
+        .is_some_and(|g| g.muted_until(editor).is_some())
+    {
+        return Err(FrameError::new(
+            ErrorCode::Forbidden,
+            "you are muted in this group",
+        ));
+    }
+    if target.from_id != Some(editor) {
+        return Err(FrameError::new(
+            ErrorCode::Forbidden,
+            "you can only edit your own messages",
+        ));
+    }
+    let window = edit_window();
+    let since = window_start(window);
+    if target.created_at.is_none_or(|c| c < since) {
+        return Err(FrameError::new(
+            ErrorCode::Forbidden,
+            format!(
+                "messages can only be edited for {} seconds after sending",
+                window.as_secs()
+            ),
+        ));
+    }
+    let edited = match db
+        .edit_message(edit.target, editor, edit.content, since)
+        .await
+    {
+        Ok(Some(m)) => m.for_clients(),
+        // the window closed or the message was deleted since the lookup
+        Ok(None) => {
+            return Err(FrameError::new(
+                ErrorCode::Forbidden,
+                "message can no longer be edited",
+            ))
+        }
+        Err(e) => {
+            error!("failed to edit the message {}", e);
+            return Err(FrameError::new(
+                ErrorCode::Internal,
+                "unable to edit message",
+            ));
+        }
+    };
+    let change = ChangeEvent::MessageEdited {
+        message: edited.clone(),
+    };
+    log_change(db, edit.target.conversation(), None, change).await;
+    let frame = ServerFrame::event(ServerEvent::MessageEdited {
+        message: Box::new(edited.clone()),
+    });
+    manager
+        .lock()
+        .await
+        .send_to_users(&target.recipients, &frame, origin);
+    Ok(edited)
+}
+
+/// Deletes a message for the caller only, or for everyone in the conversation.
+/// For everyone is open to the sender and, in groups, to moderators who outrank the sender,
+/// inside the delete window.
+/// Returns the event the other connections were sent.
+pub async fn delete_chat_message(
+    db: &Db,
+    manager: &Mutex<Manager>,
+    user_id: &str,
+    origin: Option<u64>,
+    delete: MessageDelete,
+) -> Result<ServerEvent, FrameError> {
+    let user = user_id.to_string().into_object_id();
+    let target = resolve_target(db, user_id, delete.target).await?;
+    if delete.scope == DeleteScope::Me {
+        match db.hide_message(delete.target, user).await {
+            Ok(true) => (),
+            Ok(false) => {
+                return Err(FrameError::new(
+                    ErrorCode::MessageNotFound,
+                    "message does not exist",
+                ))
+            }
+            Err(e) => {
+                error!("failed to hide the message {}", e);
+                return Err(FrameError::new(
+                    ErrorCode::Internal,
+                    "unable to delete message",
+                ));
+            }
+        }
+        let message_id = delete.target.message_id();
+        let change = ChangeEvent::MessageHidden { message_id };
+        log_change(db, delete.target.conversation(), Some(user), change).await;
+        let event = ServerEvent::MessageHidden { message_id };
+        manager
+            .lock()
+            .await
+            .send_to_user(user_id, &ServerFrame::event(event.clone()), origin);
+        return Ok(event);
+    }
+    if target.from_id != Some(user) {
+        let moderator = target.group.as_ref().is_some_and(|g| {
+            g.can(user, GroupPermission::DeleteMessages)
+                && target.from_id.is_some_and(|from| g.outranks(user, from))
+        });
+        if !moderator {
+            return Err(FrameError::new(
+                ErrorCode::Forbidden,
+                "only the sender or a group moderator above them can delete this for everyone",
+            ));
+        }
+    }
+    let window = delete_window();
+    let since = window_start(window);
+    if target.created_at.is_none_or(|c| c < since) {
+        return Err(FrameError::new(
+            ErrorCode::Forbidden,
+            format!(
+                "messages can only be deleted for everyone for {} seconds after sending",
+                window.as_secs()
+            ),
+        ));
+    }
+    let deleted = match db.tombstone_message(delete.target, user, since).await {
+        Ok(Some(m)) => m.for_clients(),
+        Ok(None) => {
+            return Err(FrameError::new(
+                ErrorCode::Forbidden,
+                "message is already deleted or can no longer be deleted",
+            ))
+        }
+        Err(e) => {
+            error!("failed to delete the message {}", e);
+            return Err(FrameError::new(
+                ErrorCode::Internal,
+                "unable to delete message",
+            ));
+        }
+    };
+    let change = ChangeEvent::MessageDeleted {
+        message: deleted.clone(),
+    };
+    log_change(db, delete.target.conversation(), None, change).await;
+    let event = ServerEvent::MessageDeleted {
+        message: Box::new(deleted),
+    };
+    manager.lock().await.send_to_users(
+        &target.recipients,
+        &ServerFrame::event(event.clone()),
+        origin,
+    );
+    Ok(event)
+}
+
+/// Adds (`add`) or takes back `user_id`'s reaction and sends the message with its new counts
+/// to everyone in the conversation.
+pub async fn react_to_message(
+    db: &Db,
+    manager: &Mutex<Manager>,
+    user_id: &str,
+    origin: Option<u64>,
+    change: ReactionChange,
+    add: bool,
+) -> Result<ChatMessage, FrameError> {
+    let emoji = change.emoji.trim();
+    if !Reaction::is_emoji(emoji) {
+        return Err(FrameError::new(
+            ErrorCode::InvalidPayload,
+            "reaction must be a single emoji",
+        ));
+    }
+    let user = user_id.to_string().into_object_id();
+    let target = resolve_target(db, user_id, change.target).await?;
+    if target.group.is_some_and(|g| g.muted_until(user).is_some()) {
+        return Err(FrameError::new(
+            ErrorCode::Forbidden,
+            "you are muted in this group",
+        ));
+    }
+    let reaction = Reaction {
+        emoji: emoji.to_string(),
+        user_id: user,
+    };
+    let message = match db.set_reaction(change.target, reaction, add).await {
+        Ok(Some(m)) => m.for_clients(),
+        Ok(None) => {
+            return Err(FrameError::new(
+                ErrorCode::MessageNotFound,
+                "message was deleted",
+            ))
+        }
+        Err(e) if e.is_invalid() => {
+            return Err(FrameError::new(ErrorCode::Forbidden, e.error()));
+        }
+        Err(e) => {
+            error!("failed to update reactions {}", e);
+            return Err(FrameError::new(
+                ErrorCode::Internal,
+                "unable to update reactions",
+            ));
+        }
+    };
+    let event = ChangeEvent::ReactionsUpdated {
+        message: message.clone(),
+    };
+    log_change(db, change.target.conversation(), None, event).await;
+    let frame = ServerFrame::event(ServerEvent::ReactionsUpdated {
+        message: Box::new(message.clone()),
+    });
+    manager
+        .lock()
+        .await
+        .send_to_users(&target.recipients, &frame, origin);
+    Ok(message)
+}
+
+// Records that `users` got the message and tells the sender's connections
+async fn record_delivery(
+    db: &Db,
+    manager: &Mutex<Manager>,
+    target: MessageRef,
+    sender: ObjectId,
+    users: &[ObjectId],
+) {
+    if users.is_empty() {
+        return;
+    }
+    let delivered = match db.mark_delivered(target, users).await {
+        Ok(delivered) => delivered,
+        Err(e) => {
+            error!("failed to record delivery {}", e);
+            return;
+        }
+    };
+    let frame = ServerFrame::event(ServerEvent::Delivered {
+        receipt: Receipt {
+            target,
+            seq: delivered.seq,
+            users: users.to_vec(),
+            at: DateTime::now(),
+        },
+    });
+    manager
+        .lock()
+        .await
+        .send_to_user(&sender.to_hex(), &frame, None);
+}
+
+/// Records that `user_id` has everything up to `target` because a history fetch or a sync
+/// reached it, and sends a `delivered` receipt to whoever sent the messages it newly covers.
+pub async fn mark_fetched(db: &Db, manager: &Mutex<Manager>, user_id: &str, target: MessageRef) {
+    let user = user_id.to_string().into_object_id();
+    let moved = match db.advance_delivered(target, user).await {
+        Ok(moved) => moved,
+        Err(e) => {
+            error!("failed to record delivery {}", e);
+            return;
+        }
+    };
+    if !moved.advanced() {
+        return;
+    }
+    let senders = match db.senders_between(target, &moved, user).await {
+        Ok(senders) => senders,
+        Err(e) => {
+            error!("failed to find who to send the receipt to {}", e);
+            return;
+        }
+    };
+    let frame = ServerFrame::event(ServerEvent::Delivered {
+        receipt: Receipt {
+            target,
+            seq: moved.to.seq,
+            users: vec![user],
+            at: DateTime::now(),
+        },
+    });
+    manager.lock().await.send_to_users(&senders, &frame, None);
+}
+
+/// Moves `user_id`'s read watermark up to the target message and sends a `read` receipt
+/// to whoever sent the messages it newly covers, and to the user's other connections.
+pub async fn mark_read(
+    db: &Db,
+    manager: &Mutex<Manager>,
+    user_id: &str,
+    origin: Option<u64>,
+    target: MessageRef,
+) -> Result<Receipt, FrameError> {
+    let reader = user_id.to_string().into_object_id();
+    resolve_entry(db, user_id, target).await?;
+    let moved = match db.mark_read(target, reader).await {
+        Ok(moved) => moved,
+        Err(e) => {
+            error!("failed to mark as read {}", e);
+            return Err(FrameError::new(
+                ErrorCode::Internal,
+                "unable to mark as read",
+            ));
+        }
+    };
+    let receipt = Receipt {
+        target,
+        seq: moved.to.seq,
+        users: vec![reader],
+        at: DateTime::now(),
+    };
+    // already read that far, nothing new to tell anyone
+    if !moved.advanced() {
+        return Ok(receipt);
+    }
+    let senders = match db.senders_between(target, &moved, reader).await {
+        Ok(senders) => senders,
+        Err(e) => {
+            error!("failed to find who to send the receipt to {}", e);
+            return Ok(receipt);
+        }
+    };
+    let frame = ServerFrame::event(ServerEvent::Read {
+        receipt: receipt.clone(),
+    });
+    {
+        let mgr = manager.lock().await;
+        mgr.send_to_users(&senders, &frame, None);
+        mgr.send_to_user(user_id, &frame, origin);
+    }
+    push_unread_counts(db, manager, target, &[reader]).await;
+    Ok(receipt)
+}
+
+// Sends each of `users` their unread count for the conversation `target` is in
+async fn push_unread_counts(
+    db: &Db,
+    manager: &Mutex<Manager>,
+    target: MessageRef,
+    users: &[ObjectId],
+) {
+    let (chat_id, group_id) = match target {
+        MessageRef::Direct { chat_id, .. } => (Some(chat_id), None),
+        MessageRef::Group { group_id, .. } => (None, Some(group_id)),
+    };
+    let counts = join_all(users.iter().map(|user| async move {
+        let count = match target {
+            MessageRef::Direct { chat_id, .. } => db.unread_in_chat(*user, chat_id).await,
+            MessageRef::Group { group_id, .. } => db.unread_in_group(*user, group_id).await,
+        };
+        (user, count)
+    }))
+    .await;
+    let mgr = manager.lock().await;
+    for (user, count) in counts {
+        match count {
+            Ok(unread) => {
+                let frame = ServerFrame::event(ServerEvent::UnreadChanged {
+                    chat_id,
+                    group_id,
+                    unread,
+                });
+                mgr.send_to_user(&user.to_hex(), &frame, None);
             }
+            Err(e) => error!("failed to count unread messages {}", e),
+        }
+    }
+}
+
+/// Tells the user's online friends when their presence is different from what they were last
+/// told. Going offline also stores `last_seen`.
+pub async fn refresh_presence(db: &Db, manager: &Mutex<Manager>, user_id: &str) {
+    let Some(presence) = manager.lock().await.presence_changed(user_id) else {
+        return;
+    };
+    let user = user_id.to_string().into_object_id();
+    let mut last_seen = None;
+    if presence == Presence::Offline {
+        let now = DateTime::now();
+        if let Err(e) = db.set_last_seen(user, now).await {
+            error!("failed to store last seen {}", e);
+        }
+        last_seen = Some(now);
+    }
+    let friends = match db.get_friend_ids(user).await {
+        Ok(friends) => friends,
+        Err(e) => {
+            error!("failed to find who to tell about presence {}", e);
+            return;
         }
+    };
+    let frame = ServerFrame::event(ServerEvent::PresenceChanged {
+        status: PresenceStatus {
+            user_id: user,
+            presence,
+            last_seen,
+        },
     });
+    manager.lock().await.send_to_users(&friends, &frame, None);
+}
+
+/// Passes a typing indicator on to the other people in the chat or group, nothing is stored.
+/// Returns the event the others got.
+pub async fn set_typing(
+    db: &Db,
+    manager: &Arc<Mutex<Manager>>,
+    user_id: &str,
+    target: ConversationRef,
+    typing: bool,
+) -> Result<ServerEvent, FrameError> {
+    let user = user_id.to_string().into_object_id();
+    if !typing {
+        manager.lock().await.stop_typing(target, user);
+        return Ok(ServerEvent::TypingStopped {
+            target,
+            user_id: user,
+        });
+    }
+    let event = ServerEvent::TypingStarted {
+        target,
+        user_id: user,
+    };
+    if manager.lock().await.typing_throttled(target, user) {
+        return Ok(event);
+    }
+    let recipients = match target {
+        ConversationRef::Direct { chat_id } => {
+            let chat = authz::chat_participant(db, user_id, chat_id).await?;
+            chat.users().to_vec()
+        }
+        ConversationRef::Group { group_id } => {
+            let group = authz::group_permission(
+                db,
+                user_id,
+                group_id,
+                GroupPermission::Post,
+                "only admins can post in this group",
+            )
+            .await?;
+            if group.muted_until(user).is_some() {
+                return Err(FrameError::new(
+                    ErrorCode::Forbidden,
+                    "you are muted in this group",
+                ));
+            }
+            group.members.into_iter().collect()
+        }
+    };
+    let started = manager.lock().await.start_typing(target, user, recipients);
+    if let Some(mut deadline) = started {
+        let manager = Arc::clone(manager);
+        tokio::spawn(async move {
+            loop {
+                sleep_until(deadline).await;
+                match manager.lock().await.expire_typing(target, user) {
+                    Some(later) => deadline = later,
+                    None => break,
+                }
+            }
+        });
+    }
+    Ok(event)
+}
+
+/// Starts (`subscribe`) or stops delivering the replies of a thread to the `conn` socket,
+/// returns the root of the thread.
+pub async fn subscribe_thread(
+    db: &Db,
+    manager: &Mutex<Manager>,
+    user_id: &str,
+    conn: u64,
+    thread: ThreadRef,
+    subscribe: bool,
+) -> Result<ChatMessage, FrameError> {
+    authz::group_member(db, user_id, thread.group_id).await?;
+    let root = thread_root(db, thread).await?;
+    let root = ChatMessage::Group(root).for_clients();
+    let mut mgr = manager.lock().await;
+    if subscribe {
+        mgr.subscribe_thread(thread.thread_id, user_id, conn);
+        // where the thread stands now, replies after this arrive as they are sent
+        let frame = ServerFrame::event(ServerEvent::ThreadUpdated {
+            message: Box::new(root.clone()),
+        });
+        mgr.send_to_conn(user_id, conn, &frame);
+    } else {
+        mgr.unsubscribe_thread(thread.thread_id, user_id, conn);
+    }
+    Ok(root)
+}
+
+/// The message a thread hangs off. Replies can not start threads of their own.
+pub async fn thread_root(db: &Db, thread: ThreadRef) -> Result<GroupMessage, FrameError> {
+    match db.find_group_message(thread.thread_id).await {
+        Some(root) if root.group_id == Some(thread.group_id) && root.thread_id.is_none() => {
+            Ok(root)
+        }
+        _ => Err(FrameError::new(
+            ErrorCode::MessageNotFound,
+            "thread does not exist",
+        )),
+    }
+}
+
+// A message a client pointed at, after checking the caller belongs to its conversation
+struct Target {
+    recipients: Vec<ObjectId>,
+    group: Option<Group>,
+    from_id: Option<ObjectId>,
+    created_at: Option<DateTime>,
+}
+
+async fn resolve_target(db: &Db, user_id: &str, target: MessageRef) -> Result<Target, FrameError> {
+    let message_id = target.message_id();
+    let (recipients, group, message) = match target {
+        MessageRef::Direct { chat_id, .. } => {
+            let chat = authz::chat_participant(db, user_id, chat_id).await?;
+            let message = db
+                .find_message(message_id)
+                .await
+                .filter(|m| m.chat_id == Some(chat_id))
+                .map(|m| (m.from_id, m.created_at));
+            (chat.users().to_vec(), None, message)
+        }
+        MessageRef::Group { group_id, .. } => {
+            let group = authz::group_member(db, user_id, group_id).await?;
+            let message = db
+                .find_group_message(message_id)
+                .await
+                .filter(|m| m.group_id == Some(group_id))
+                .map(|m| (m.from_id, m.created_at));
+            (
+                group.members.iter().copied().collect(),
+                Some(group),
+                message,
+            )
+        }
+    };
+    match message {
+        Some((from_id, created_at)) => Ok(Target {
+            recipients,
+            group,
+            from_id,
+            created_at,
+        }),
+        None => Err(FrameError::new(
+            ErrorCode::MessageNotFound,
+            "message does not exist",
+        )),
+    }
+}
+
+// Like resolve_target, but any entry in the timeline counts, system messages too
+async fn resolve_entry(db: &Db, user_id: &str, target: MessageRef) -> Result<(), FrameError> {
+    match target {
+        MessageRef::Direct { chat_id, .. } => {
+            authz::chat_participant(db, user_id, chat_id).await?;
+        }
+        MessageRef::Group { group_id, .. } => {
+            authz::group_member(db, user_id, group_id).await?;
+        }
+    }
+    match db.message_in_conversation(target).await {
+        Ok(true) => Ok(()),
+        Ok(false) => Err(FrameError::new(
+            ErrorCode::MessageNotFound,
+            "message does not exist",
+        )),
+        Err(e) => {
+            error!("failed to look up the message {}", e);
+            Err(FrameError::new(
+                ErrorCode::Internal,
+                "unable to find the message",
+            ))
+        }
+    }
 }
 
-async fn match_result(
-    sender: Arc<Mutex<SplitSink<WebSocket, Message>>>,
-    result: Option<InsertOneResult>,
+fn window_start(window: Duration) -> DateTime {
+    DateTime::from_millis(DateTime::now().timestamp_millis() - window.as_millis() as i64)
+}
+
+/// Writes a system message into its timeline and delivers it to `recipients` like any other
+/// message. What it records has already happened, so a failure here is only logged.
+pub async fn post_system_message<'a>(
+    db: &Db,
+    manager: &Mutex<Manager>,
+    msg: SystemMessage,
+    recipients: impl IntoIterator<Item = &'a ObjectId>,
 ) {
-    match result {
-        Some(r) => {
-            info!("database response : [{}]", r.inserted_id);
+    let conversation = match (msg.chat_id, msg.group_id) {
+        (Some(chat_id), _) => Some(ConversationRef::Direct { chat_id }),
+        (None, Some(group_id)) => Some(ConversationRef::Group { group_id }),
+        (None, None) => None,
+    };
+    match db.add_message_to_db(ChatMessage::System(msg)).await {
+        Ok((stored, _)) => {
+            if let Some(conversation) = conversation {
+                let change = ChangeEvent::Message {
+                    message: stored.clone(),
+                };
+                log_change(db, conversation, None, change).await;
+            }
+            let frame = ServerFrame::message(stored);
+            manager.lock().await.send_to_users(recipients, &frame, None);
+        }
+        Err(e) => error!("failed to add the system message {}", e),
+    }
+}
+
+// Adds to the change log for clients that sync later. Failing that only costs them a refetch.
+async fn log_change(
+    db: &Db,
+    conversation: ConversationRef,
+    only_for: Option<ObjectId>,
+    event: ChangeEvent,
+) {
+    if let Err(e) = db
+        .record_change(Change::new(conversation, only_for, event))
+        .await
+    {
+        error!("failed to log the change {}", e);
+    }
+}
+
+/// Streams every change the user missed to the `conn` socket, oldest first, and ends it with a
+/// `synced` event, which is also returned. At most MAX_SYNC_CHANGES are sent, `more` asks for
+/// another round.
+pub async fn sync_changes(
+    db: &Db,
+    manager: &Mutex<Manager>,
+    user_id: &str,
+    conn: u64,
+    req: SyncRequest,
+) -> Result<ServerEvent, FrameError> {
+    let synced = stream_changes(db, manager, user_id, conn, req).await?;
+    let frame = ServerFrame::event(synced.clone());
+    manager.lock().await.send_to_conn(user_id, conn, &frame);
+    Ok(synced)
+}
+
+async fn stream_changes(
+    db: &Db,
+    manager: &Mutex<Manager>,
+    user_id: &str,
+    conn: u64,
+    req: SyncRequest,
+) -> Result<ServerEvent, FrameError> {
+    check_sync_request(&req)?;
+    let internal = |e: MyError| {
+        error!("failed to sync {}", e);
+        FrameError::new(ErrorCode::Internal, "unable to sync")
+    };
+    let user = user_id.to_string().into_object_id();
+    let (chats, groups) = db.get_conversation_ids(user).await.map_err(internal)?;
+    // changes past this one may still have earlier ones landing before them
+    let settled = db.settled_change_seq().await.map_err(internal)?;
+    let mut expired = false;
+    let mut resync = vec![];
+    let scope = match req.since {
+        Some(since) => {
+            // the log only goes back so far, anything before its oldest change is lost
+            let oldest = db.oldest_change_seq().await.map_err(internal)?;
+            expired = oldest.is_some_and(|oldest| since + 1 < oldest);
+            doc! {
+                "seq":{"$gt":since},
+                "$or":[{"chat_id":{"$in":&chats}},{"group_id":{"$in":&groups}}]
+            }
         }
         None => {
-            error!("failed to add the message");
-            #[allow(warnings)]
-            sender.lock().await.send(Message::text(
-                json!({
-                    "err":"unable to send message"
-                })
-                .to_string(),
-            ));
+            let mut branches = vec![];
+            for cursor in req.conversations {
+                let key = match cursor.target {
+                    ConversationRef::Direct { chat_id } if chats.contains(&chat_id) => {
+                        doc! {"chat_id":chat_id}
+                    }
+                    ConversationRef::Group { group_id } if groups.contains(&group_id) => {
+                        doc! {"group_id":group_id}
+                    }
+                    _ => {
+                        return Err(FrameError::new(
+                            ErrorCode::Forbidden,
+                            "you are not in every conversation you asked for",
+                        ))
+                    }
+                };
+                let after = match cursor.after_seq {
+                    Some(seq) => db
+                        .find_message_id_by_seq(cursor.target, seq)
+                        .await
+                        .map_err(internal)?,
+                    None => cursor.after,
+                };
+                let logged = match after {
+                    Some(id) => db.change_seq_for_message(id).await.map_err(internal)?,
+                    None => None,
+                };
+                match logged {
+                    Some(seq) => {
+                        let mut branch = key;
+                        branch.insert("seq", doc! {"$gt":seq});
+                        branches.push(branch);
+                    }
+                    None => resync.push(cursor.target),
+                }
+            }
+            if branches.is_empty() {
+                return Ok(ServerEvent::Synced {
+                    seq: settled,
+                    more: false,
+                    expired,
+                    resync,
+                });
+            }
+            doc! {"$or":branches}
+        }
+    };
+    let visible = doc! {"$or":[{"only_for":{"$exists":false}},{"only_for":user}]};
+    let filter = doc! {"$and":[scope, visible, {"seq":{"$lte":settled}}]};
+    let mut changes = db
+        .get_changes(filter, MAX_SYNC_CHANGES + 1)
+        .await
+        .map_err(internal)?;
+    let more = changes.len() as i64 > MAX_SYNC_CHANGES;
+    changes.truncate(MAX_SYNC_CHANGES as usize);
+    let seq = match (changes.last(), more) {
+        (Some(last), true) => last.seq,
+        _ => settled.max(req.since.unwrap_or(0)),
+    };
+    // the newest message this sync hands over in each conversation
+    let mut reached: HashMap<ConversationRef, ObjectId> = HashMap::new();
+    for change in &changes {
+        if let (Some(conversation), ChangeEvent::Message { message }) =
+            (change.conversation(), &change.event)
+        {
+            if let Some(id) = message.id().filter(|_| !message.in_thread()) {
+                let newest = reached.entry(conversation).or_insert(id);
+                *newest = (*newest).max(id);
+            }
+        }
+    }
+    {
+        let mgr = manager.lock().await;
+        for change in changes {
+            let frame = ServerFrame::new(None, ServerOp::Change(Box::new(change)));
+            mgr.send_to_conn(user_id, conn, &frame);
+        }
+    }
+    for (conversation, newest) in reached {
+        mark_fetched(db, manager, user_id, conversation.message(newest)).await;
+    }
+    Ok(ServerEvent::Synced {
+        seq,
+        more,
+        expired,
+        resync,
+    })
+}
+
+// The shape of a sync request, checked before anything is looked up
+fn check_sync_request(req: &SyncRequest) -> Result<(), FrameError> {
+    if req.since.is_some() != req.conversations.is_empty() {
+        return Err(FrameError::new(
+            ErrorCode::InvalidPayload,
+            "send either since or conversations",
+        ));
+    }
+    if req
+        .conversations
+        .iter()
+        .any(|c| c.after.is_some() == c.after_seq.is_some())
+    {
+        return Err(FrameError::new(
+            ErrorCode::InvalidPayload,
+            "each conversation needs either after or after_seq",
+        ));
+    }
+    Ok(())
+}
+
+async fn store_message(
+    db: &Db,
+    msg: ChatMessage,
+) -> Result<(ChatMessage, ObjectId, Ack), FrameError> {
+    let result = db.add_message_to_db(msg).await;
+    match result {
+        Ok((stored, duplicate)) => match (stored.id(), stored.created_at()) {
+            (Some(id), Some(created_at)) => {
+                info!("database response : [{}] duplicate : {}", id, duplicate);
+                let ack = Ack {
+                    id: Some(id),
+                    created_at,
+                    duplicate,
+                };
+                Ok((stored, id, ack))
+            }
+            _ => {
+                error!("stored message without id or timestamp");
+                Err(FrameError::new(
+                    ErrorCode::Internal,
+                    "unable to send message",
+                ))
+            }
+        },
+        Err(e) if e.is_invalid() => Err(FrameError::new(ErrorCode::InvalidPayload, e.error())),
+        Err(e) => {
+            error!("failed to add the message {}", e);
+            Err(FrameError::new(
+                ErrorCode::Internal,
+                "unable to send message",
+            ))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn connect(manager: &mut Manager, user: ObjectId) -> (u64, mpsc::UnboundedReceiver<Outbound>) {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let client = Client {
+            sender: tx,
+            session_id: String::from("session"),
+            last_active: Instant::now(),
+        };
+        (manager.insert(user.to_hex(), client), rx)
+    }
+
+    #[test]
+    fn typing_is_throttled_then_expires() {
+        let mut manager = Manager::new();
+        let (typist, reader) = (ObjectId::new(), ObjectId::new());
+        let (_, mut typist_rx) = connect(&mut manager, typist);
+        let (_, mut reader_rx) = connect(&mut manager, reader);
+        let target = ConversationRef::Direct {
+            chat_id: ObjectId::new(),
+        };
+
+        assert!(!manager.typing_throttled(target, typist));
+        assert!(manager
+            .start_typing(target, typist, vec![typist, reader])
+            .is_some());
+        assert!(reader_rx.try_recv().is_ok());
+        assert!(typist_rx.try_recv().is_err());
+
+        // a repeat right away is swallowed, one after the throttle goes out again
+        assert!(manager.typing_throttled(target, typist));
+        manager.typing.get_mut(&(target, typist)).unwrap().last_sent -= TYPING_THROTTLE;
+        assert!(!manager.typing_throttled(target, typist));
+        // the indicator is already being watched, no second expiry task
+        assert!(manager
+            .start_typing(target, typist, vec![typist, reader])
+            .is_none());
+        assert!(reader_rx.try_recv().is_ok());
+
+        assert!(manager.expire_typing(target, typist).is_some());
+        manager.typing.get_mut(&(target, typist)).unwrap().expires = Instant::now();
+        assert!(manager.expire_typing(target, typist).is_none());
+        assert!(!manager.typing.contains_key(&(target, typist)));
+        match reader_rx.try_recv() {
+            Ok(Outbound::Frame(ServerFrame {
+                op: ServerOp::Event(ServerEvent::TypingStopped { user_id, .. }),
+                ..
+            })) => assert_eq!(user_id, typist),
+            _ => panic!("expected typing_stopped"),
+        }
+    }
+
+    #[test]
+    fn presence_goes_online_away_and_offline() {
+        let mut manager = Manager::new();
+        let user = ObjectId::new();
+        let key = user.to_hex();
+        assert_eq!(manager.presence_of(&key), Presence::Offline);
+        assert_eq!(manager.presence_changed(&key), None);
+
+        let (conn, _rx) = connect(&mut manager, user);
+        assert_eq!(manager.presence_changed(&key), Some(Presence::Online));
+        assert_eq!(manager.presence_changed(&key), None);
+
+        let client = manager
+            .clients
+            .get_mut(&key)
+            .unwrap()
+            .get_mut(&conn)
+            .unwrap();
+        client.last_active -= AWAY_AFTER;
+        assert_eq!(manager.presence_changed(&key), Some(Presence::Away));
+        assert_eq!(manager.present_users(), vec![key.clone()]);
+
+        // activity after being away is reported so the caller can tell friends
+        assert!(manager.touch(&key, conn));
+        assert_eq!(manager.presence_changed(&key), Some(Presence::Online));
+        assert!(!manager.touch(&key, conn));
+
+        manager.remove(&key, conn);
+        assert_eq!(manager.presence_changed(&key), Some(Presence::Offline));
+        assert!(manager.present_users().is_empty());
+    }
+
+    fn sync_request(value: Value) -> SyncRequest {
+        from_value(value).unwrap()
+    }
+
+    #[test]
+    fn sync_needs_since_or_conversations() {
+        let chat = ObjectId::new();
+        let neither = sync_request(json!({}));
+        assert!(check_sync_request(&neither).is_err());
+        let both = sync_request(json!({
+            "since":4,
+            "conversations":[{"type":"direct","chat_id":chat,"after_seq":1}]
+        }));
+        assert!(check_sync_request(&both).is_err());
+        assert!(check_sync_request(&sync_request(json!({"since":0}))).is_ok());
+    }
+
+    #[test]
+    fn sync_cursor_needs_exactly_one_position() {
+        let chat = ObjectId::new();
+        for cursor in [
+            json!({"type":"direct","chat_id":chat}),
+            json!({"type":"direct","chat_id":chat,"after":ObjectId::new(),"after_seq":3}),
+        ] {
+            let req = sync_request(json!({ "conversations": [cursor] }));
+            let err = check_sync_request(&req).unwrap_err();
+            assert_eq!(err.code, ErrorCode::InvalidPayload);
         }
+        let req = sync_request(json!({
+            "conversations":[
+                {"type":"direct","chat_id":chat,"after":ObjectId::new()},
+                {"type":"group","group_id":ObjectId::new(),"after_seq":7}
+            ]
+        }));
+        assert!(check_sync_request(&req).is_ok());
     }
 }
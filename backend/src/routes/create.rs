@@ -4,42 +4,110 @@ use axum::{
     body::{to_bytes, Body}, extract::Request, http::StatusCode, response::IntoResponse, Extension, Json
 };
 use log::{error};
-use serde_json::{from_slice, from_str, json};
+use serde_json::{from_slice, json};
+use tokio::sync::Mutex;
 
-use crate::{db::Db, models::{ChatRequest, Members}, utils::extract_cookie};
+use crate::{
+    db::{Db, IntoObjectId},
+    models::{validate_group_details, ChatRequest, NewGroup, SystemEvent, SystemMessage},
+    routes::chat::{post_system_message, Manager},
+    utils::extract_cookie,
+};
 
 pub async fn handle_group_creation(
     Extension(db): Extension<Arc<Db>>,
+    Extension(manager): Extension<Arc<Mutex<Manager>>>,
     req: Request<Body>,
 ) -> impl IntoResponse {
     let (parts, body) = req.into_parts();
-    let id = extract_cookie(parts).await.unwrap().sub;
-    let bytes = to_bytes(body, usize::MAX).await.unwrap();
-    let data = from_slice::<Members>(&bytes).unwrap();
-    let res = db.create_group_chat(id, data.members).await;
+    let id = match extract_cookie(parts, &db).await {
+        Ok(c) => c.sub,
+        Err(e) => {
+            return (StatusCode::UNAUTHORIZED, Json(json!({
+                "success":false,
+                "err":e
+            })))
+        }
+    };
+    // old clients without a name end up here too
+    let data = to_bytes(body, usize::MAX)
+        .await
+        .map_err(|e| e.to_string())
+        .and_then(|b| from_slice::<NewGroup>(&b).map_err(|e| e.to_string()));
+    let data = match data {
+        Ok(data) => data,
+        Err(e) => {
+            error!("{}", e);
+            return (StatusCode::BAD_REQUEST, Json(json!({
+                "success":false,
+                "err":"invalid request body"
+            })));
+        }
+    };
+    if let Err(e) = validate_group_details(Some(&data.name), data.description.as_deref()) {
+        return (StatusCode::BAD_REQUEST, Json(json!({
+            "success":false,
+            "err":e
+        })));
+    }
+    let name = data.name.clone();
+    let res = db.create_group_chat(id.clone(), data).await;
     match res {
-        Some(r) => Json(json!({
-            "group_id":r.inserted_id,
-            "success":true
-        })),
+        Some(r) => {
+            // the event also tells members who are online that the group exists
+            if let Some(group) = db.find_group(r.inserted_id.as_object_id().unwrap()).await {
+                let event = SystemEvent::GroupCreated { name };
+                let msg = SystemMessage::group(group.id.unwrap(), id.into_object_id(), event);
+                post_system_message(&db, &manager, msg, &group.members).await;
+            }
+            (StatusCode::OK, Json(json!({
+                "group_id":r.inserted_id,
+                "success":true
+            })))
+        }
         None => {
             error!("unable to create group");
-            Json(json!({
+            (StatusCode::INTERNAL_SERVER_ERROR, Json(json!({
                 "success":false
-            }))
+            })))
         }
     }
 }
 
-pub async fn handle_chat_creation(Extension(db): Extension<Arc<Db>>,req: Request<Body>) -> impl IntoResponse{
+pub async fn handle_chat_creation(
+    Extension(db): Extension<Arc<Db>>,
+    Extension(manager): Extension<Arc<Mutex<Manager>>>,
+    req: Request<Body>,
+) -> impl IntoResponse {
     let (parts, body) = req.into_parts();
-    let id = extract_cookie(parts).await.unwrap().sub;
-    let bytes = to_bytes(body, usize::MAX).await.unwrap();
-    let s = String::from_utf8_lossy(&bytes);
-    let second = from_str::<ChatRequest>(&s).unwrap();
-    let chat = db.create_chat(id, second.second.unwrap().to_hex()).await;
+    let id = match extract_cookie(parts, &db).await {
+        Ok(c) => c.sub,
+        Err(e) => {
+            return (StatusCode::UNAUTHORIZED, Json(json!({
+                "err":e
+            })))
+        }
+    };
+    let second = to_bytes(body, usize::MAX)
+        .await
+        .map_err(|e| e.to_string())
+        .and_then(|b| from_slice::<ChatRequest>(&b).map_err(|e| e.to_string()));
+    let second = match second.map(|r| r.second) {
+        Ok(Some(second)) => second,
+        Ok(None) | Err(_) => {
+            return (StatusCode::BAD_REQUEST, Json(json!({
+                "err":"invalid request body"
+            })))
+        }
+    };
+    let chat = db.create_chat(id.clone(), second.to_hex()).await;
     match chat {
         Ok(r) => {
+            if let Some(chat_id) = r.inserted_id.as_object_id() {
+                let creator = id.into_object_id();
+                let msg = SystemMessage::chat(chat_id, creator, SystemEvent::ChatCreated);
+                post_system_message(&db, &manager, msg, &[creator, second]).await;
+            }
             (StatusCode::OK,Json(json!({
                 "id":r.inserted_id,
                 "success":true
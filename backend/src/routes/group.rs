@@ -1,17 +1,31 @@
-use std::usize;
+use std::sync::Arc;
 
 use axum::{
-    body::{to_bytes, Body},
-    http::{Request, StatusCode},
+    body::{to_bytes, Body, Bytes},
+    http::{request::Parts, Request, StatusCode},
     response::IntoResponse,
     Extension, Json,
 };
 use log::error;
-use mongodb::bson::{from_slice_utf8_lossy};
-use serde::{Deserialize, Serialize};
-use serde_json::json;
+use mongodb::bson::oid::ObjectId;
+use mongodb::bson::DateTime;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::{json, Value};
+use tokio::sync::Mutex;
 
-use crate::{db::Db, utils::extract_cookie};
+use crate::{
+    authz,
+    db::Db,
+    models::{
+        validate_group_details, Group, GroupInvite, GroupJoinRequest, GroupPermission, GroupRole,
+        GroupRoleChange, GroupUpdate, InviteJoin, InviteRevoke, JoinRequestAction, LeaveGroup,
+        MemberAction, MuteMember, MyError, NewInvite, NewJoinRequest, OwnershipTransfer,
+        ServerEvent, ServerFrame, SystemEvent, SystemMessage, MAX_INVITE_EXPIRY_SECS,
+        MAX_MUTE_SECS,
+    },
+    routes::chat::{post_system_message, Manager},
+    utils::{extract_cookie, new_token_id},
+};
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 #[serde(tag = "action")]
@@ -33,60 +47,1279 @@ struct RemoveMember {
 }
 
 pub async fn add_or_remove_members(
-    Extension(db): Extension<Db>,
+    Extension(db): Extension<Arc<Db>>,
+    Extension(manager): Extension<Arc<Mutex<Manager>>>,
     req: Request<Body>,
 ) -> impl IntoResponse {
     let (parts, body) = req.into_parts();
-    let id = extract_cookie(parts).await.unwrap().sub;
-    let bytes = to_bytes(body, usize::MAX).await.unwrap();
-    let action = from_slice_utf8_lossy::<HandleMember>(&bytes);
-    match action {
-        Ok(HandleMember::Add(r)) => {
-            let res = db
-                .add_or_remove_members(id, r.group_id, r.user_ids, "add")
-                .await;
-            match res {
-                Ok(_) => (
-                    StatusCode::OK,
-                    Json(json!({
-                        "success":true
-                    })),
-                ),
-                Err(e) => (
-                    StatusCode::BAD_REQUEST,
-                    Json(json!({
-                        "err":e.error()
-                    })),
-                ),
+    let id = match authenticate(parts, &db).await {
+        Ok(id) => id,
+        Err(reply) => return reply,
+    };
+    let actor = ObjectId::parse_str(&id).unwrap();
+    let bytes = match read_body(body).await {
+        Ok(bytes) => bytes,
+        Err(reply) => return reply,
+    };
+    let (group_id, user_ids, action) = match parse_body::<HandleMember>(&bytes) {
+        Ok(HandleMember::Add(r)) => (r.group_id, r.user_ids, "add"),
+        Ok(HandleMember::Remove(r)) => (r.group_id, r.user_ids, "remove"),
+        Err(e) => return e,
+    };
+    // only the users whose membership actually changed get an event
+    let before = match ObjectId::parse_str(&group_id) {
+        Ok(g) => db
+            .find_group(g)
+            .await
+            .map(|g| g.members)
+            .unwrap_or_default(),
+        Err(_) => Default::default(),
+    };
+    let requested: Vec<ObjectId> = user_ids
+        .iter()
+        .filter_map(|u| ObjectId::parse_str(u).ok())
+        .collect();
+    let res = db
+        .add_or_remove_members(id, group_id, user_ids, action)
+        .await;
+    match res {
+        Ok(group) => {
+            let group_id = group.id.unwrap();
+            let changed: Vec<ObjectId> = requested
+                .into_iter()
+                .filter(|u| before.contains(u) != group.members.contains(u))
+                .collect();
+            if action == "add" && !changed.is_empty() {
+                let event = SystemEvent::MembersAdded { users: changed };
+                let msg = SystemMessage::group(group_id, actor, event);
+                post_system_message(&db, &manager, msg, &group.members).await;
+            } else if action == "remove" {
+                let recipients: Vec<ObjectId> = group
+                    .members
+                    .iter()
+                    .chain(changed.iter())
+                    .copied()
+                    .collect();
+                for user in changed {
+                    let event = SystemEvent::MemberKicked { user };
+                    let msg = SystemMessage::group(group_id, actor, event);
+                    post_system_message(&db, &manager, msg, &recipients).await;
+                }
             }
+            (
+                StatusCode::OK,
+                Json(json!({
+                    "success":true
+                })),
+            )
+        }
+        Err(e) => (
+            StatusCode::BAD_REQUEST,
+            Json(json!({
+                "err":e.error()
+            })),
+        ),
+    }
+}
+
+pub async fn update_group(
+    Extension(db): Extension<Arc<Db>>,
+    Extension(manager): Extension<Arc<Mutex<Manager>>>,
+    req: Request<Body>,
+) -> impl IntoResponse {
+    let (parts, body) = req.into_parts();
+    let id = match authenticate(parts, &db).await {
+        Ok(id) => id,
+        Err(reply) => return reply,
+    };
+    let bytes = match read_body(body).await {
+        Ok(bytes) => bytes,
+        Err(reply) => return reply,
+    };
+    let update = match parse_body::<GroupUpdate>(&bytes) {
+        Ok(u) => u,
+        Err(e) => return e,
+    };
+    if let Err(e) = validate_group_details(update.name.as_deref(), update.description.as_deref()) {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(json!({
+                "err":e
+            })),
+        );
+    }
+    if let Err(e) = authz::group_permission(
+        &db,
+        &id,
+        update.group_id,
+        GroupPermission::UpdateDetails,
+        "only admins can update the group",
+    )
+    .await
+    {
+        return (
+            e.code.status(),
+            Json(json!({
+                "err":e.message
+            })),
+        );
+    }
+    let mut events = vec![];
+    if let Some(name) = &update.name {
+        events.push(SystemEvent::GroupRenamed { name: name.clone() });
+    }
+    if let Some(description) = &update.description {
+        events.push(SystemEvent::GroupDescriptionChanged {
+            description: description.clone(),
+        });
+    }
+    if let Some(avatar) = &update.avatar {
+        events.push(SystemEvent::GroupAvatarChanged {
+            avatar: avatar.clone(),
+        });
+    }
+    if let Some(settings) = &update.settings {
+        events.push(SystemEvent::GroupSettingsChanged {
+            settings: settings.clone(),
+        });
+    }
+    let res = db.update_group_details(update).await;
+    match res {
+        Ok(Some(group)) => {
+            announce_group_update(&manager, &group).await;
+            let actor = ObjectId::parse_str(&id).unwrap();
+            for event in events {
+                let msg = SystemMessage::group(group.id.unwrap(), actor, event);
+                post_system_message(&db, &manager, msg, &group.members).await;
+            }
+            (
+                StatusCode::OK,
+                Json(json!({
+                    "success":true,
+                    "group":group
+                })),
+            )
+        }
+        Ok(None) => (
+            StatusCode::NOT_FOUND,
+            Json(json!({
+                "err":"group does not exist"
+            })),
+        ),
+        Err(e) => {
+            error!("{}", e);
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({
+                    "err":e.error()
+                })),
+            )
+        }
+    }
+}
+
+async fn announce_group_update(manager: &Mutex<Manager>, group: &Group) {
+    let frame = ServerFrame::event(ServerEvent::GroupUpdated {
+        group: Box::new(group.clone()),
+    });
+    manager
+        .lock()
+        .await
+        .send_to_users(&group.members, &frame, None);
+}
+
+type Reply = (StatusCode, Json<Value>);
+
+// The caller's user id from the access cookie
+async fn authenticate(parts: Parts, db: &Db) -> Result<String, Reply> {
+    match extract_cookie(parts, db).await {
+        Ok(claims) => Ok(claims.sub),
+        Err(e) => Err((
+            StatusCode::UNAUTHORIZED,
+            Json(json!({
+                "err":e
+            })),
+        )),
+    }
+}
+
+fn parse_body<T: DeserializeOwned>(bytes: &[u8]) -> Result<T, Reply> {
+    serde_json::from_slice::<T>(bytes).map_err(|e| {
+        error!("{}", e);
+        (
+            StatusCode::BAD_REQUEST,
+            Json(json!({
+                "err":"invalid request body"
+            })),
+        )
+    })
+}
+
+async fn read_body(body: Body) -> Result<Bytes, Reply> {
+    to_bytes(body, usize::MAX).await.map_err(|e| {
+        error!("{}", e);
+        (
+            StatusCode::BAD_REQUEST,
+            Json(json!({
+                "err":"invalid request body"
+            })),
+        )
+    })
+}
+
+fn group_changed(res: Result<Option<Group>, MyError>, missing: &str) -> Result<Group, Reply> {
+    match res {
+        Ok(Some(group)) => Ok(group),
+        Ok(None) => Err((
+            StatusCode::CONFLICT,
+            Json(json!({
+                "err":missing
+            })),
+        )),
+        Err(e) => {
+            error!("{}", e);
+            Err((
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({
+                    "err":e.error()
+                })),
+            ))
+        }
+    }
+}
+
+// Owners manage admins, admins manage moderators. Anyone but the owner may step down.
+pub async fn change_role(
+    Extension(db): Extension<Arc<Db>>,
+    Extension(manager): Extension<Arc<Mutex<Manager>>>,
+    req: Request<Body>,
+) -> impl IntoResponse {
+    let (parts, body) = req.into_parts();
+    let id = match authenticate(parts, &db).await {
+        Ok(id) => id,
+        Err(reply) => return reply,
+    };
+    let bytes = match read_body(body).await {
+        Ok(bytes) => bytes,
+        Err(reply) => return reply,
+    };
+    let change = match parse_body::<GroupRoleChange>(&bytes) {
+        Ok(c) => c,
+        Err(e) => return e,
+    };
+    if change.role == GroupRole::Owner {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(json!({
+                "err":"use the transfer ownership endpoint to change the owner"
+            })),
+        );
+    }
+    let group = match authz::group_member(&db, &id, change.group_id).await {
+        Ok(g) => g,
+        Err(e) => {
+            return (
+                e.code.status(),
+                Json(json!({
+                    "err":e.message
+                })),
+            )
+        }
+    };
+    let actor = group.role_of(ObjectId::parse_str(&id).unwrap());
+    let current = match group.role_of(change.user_id) {
+        Some(r) => r,
+        None => {
+            return (
+                StatusCode::BAD_REQUEST,
+                Json(json!({
+                    "err":"user is not a member of this group"
+                })),
+            )
+        }
+    };
+    let permission = if change.role == GroupRole::Admin || current == GroupRole::Admin {
+        GroupPermission::ManageAdmins
+    } else {
+        GroupPermission::ManageModerators
+    };
+    let stepping_down =
+        change.user_id.to_hex() == id && current != GroupRole::Owner && change.role < current;
+    let allowed = stepping_down
+        || (group.can(ObjectId::parse_str(&id).unwrap(), permission)
+            && actor.is_some_and(|a| a > current));
+    if !allowed {
+        return (
+            StatusCode::FORBIDDEN,
+            Json(json!({
+                "err":"you are not allowed to change this member's role"
+            })),
+        );
+    }
+    let res = db
+        .set_group_role(change.group_id, change.user_id, change.role)
+        .await;
+    match group_changed(res, "user is no longer a member of this group") {
+        Ok(group) => {
+            announce_group_update(&manager, &group).await;
+            let event = SystemEvent::RoleChanged {
+                user: change.user_id,
+                role: change.role,
+            };
+            let actor = ObjectId::parse_str(&id).unwrap();
+            let msg = SystemMessage::group(change.group_id, actor, event);
+            post_system_message(&db, &manager, msg, &group.members).await;
+            (
+                StatusCode::OK,
+                Json(json!({
+                    "success":true,
+                    "group":group
+                })),
+            )
+        }
+        Err(e) => e,
+    }
+}
+
+// The previous owner stays on as an admin
+pub async fn transfer_ownership(
+    Extension(db): Extension<Arc<Db>>,
+    Extension(manager): Extension<Arc<Mutex<Manager>>>,
+    req: Request<Body>,
+) -> impl IntoResponse {
+    let (parts, body) = req.into_parts();
+    let id = match authenticate(parts, &db).await {
+        Ok(id) => id,
+        Err(reply) => return reply,
+    };
+    let bytes = match read_body(body).await {
+        Ok(bytes) => bytes,
+        Err(reply) => return reply,
+    };
+    let transfer = match parse_body::<OwnershipTransfer>(&bytes) {
+        Ok(t) => t,
+        Err(e) => return e,
+    };
+    let group = match authz::group_permission(
+        &db,
+        &id,
+        transfer.group_id,
+        GroupPermission::TransferOwnership,
+        "only the owner can transfer ownership",
+    )
+    .await
+    {
+        Ok(g) => g,
+        Err(e) => {
+            return (
+                e.code.status(),
+                Json(json!({
+                    "err":e.message
+                })),
+            )
+        }
+    };
+    if transfer.user_id.to_hex() == id || group.role_of(transfer.user_id).is_none() {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(json!({
+                "err":"ownership can only go to another member of the group"
+            })),
+        );
+    }
+    let res = db
+        .transfer_group_ownership(
+            transfer.group_id,
+            ObjectId::parse_str(&id).unwrap(),
+            transfer.user_id,
+        )
+        .await;
+    match group_changed(res, "the group changed, try again") {
+        Ok(group) => {
+            announce_group_update(&manager, &group).await;
+            let event = SystemEvent::OwnershipTransferred {
+                user: transfer.user_id,
+            };
+            let actor = ObjectId::parse_str(&id).unwrap();
+            let msg = SystemMessage::group(transfer.group_id, actor, event);
+            post_system_message(&db, &manager, msg, &group.members).await;
+            (
+                StatusCode::OK,
+                Json(json!({
+                    "success":true,
+                    "group":group
+                })),
+            )
+        }
+        Err(e) => e,
+    }
+}
+
+pub async fn create_invite(
+    Extension(db): Extension<Arc<Db>>,
+    req: Request<Body>,
+) -> impl IntoResponse {
+    let (parts, body) = req.into_parts();
+    let id = match authenticate(parts, &db).await {
+        Ok(id) => id,
+        Err(reply) => return reply,
+    };
+    let bytes = match read_body(body).await {
+        Ok(bytes) => bytes,
+        Err(reply) => return reply,
+    };
+    let details = match parse_body::<NewInvite>(&bytes) {
+        Ok(d) => d,
+        Err(e) => return e,
+    };
+    if details
+        .expires_in
+        .is_some_and(|s| s == 0 || s > MAX_INVITE_EXPIRY_SECS)
+        || details.max_uses == Some(0)
+    {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(json!({
+                "err":format!(
+                    "expires_in must be between 1 and {} seconds and max_uses at least 1",
+                    MAX_INVITE_EXPIRY_SECS
+                )
+            })),
+        );
+    }
+    if let Err(e) = authz::group_permission(
+        &db,
+        &id,
+        details.group_id,
+        GroupPermission::AddMembers,
+        "you are not allowed to invite members to this group",
+    )
+    .await
+    {
+        return (
+            e.code.status(),
+            Json(json!({
+                "err":e.message
+            })),
+        );
+    }
+    let invite = GroupInvite::new(new_token_id(), ObjectId::parse_str(&id).unwrap(), details);
+    match db.create_invite(invite).await {
+        Ok(invite) => (
+            StatusCode::OK,
+            Json(json!({
+                "success":true,
+                "invite":invite
+            })),
+        ),
+        Err(e) => {
+            error!("{}", e);
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({
+                    "err":e.error()
+                })),
+            )
+        }
+    }
+}
+
+// The creator of an invite can always revoke it, admins can revoke any
+pub async fn revoke_invite(
+    Extension(db): Extension<Arc<Db>>,
+    req: Request<Body>,
+) -> impl IntoResponse {
+    let (parts, body) = req.into_parts();
+    let id = match authenticate(parts, &db).await {
+        Ok(id) => id,
+        Err(reply) => return reply,
+    };
+    let bytes = match read_body(body).await {
+        Ok(bytes) => bytes,
+        Err(reply) => return reply,
+    };
+    let revoke = match parse_body::<InviteRevoke>(&bytes) {
+        Ok(r) => r,
+        Err(e) => return e,
+    };
+    let invite = match db.find_invite_with_id(revoke.invite_id).await {
+        Some(i) => i,
+        None => {
+            return (
+                StatusCode::NOT_FOUND,
+                Json(json!({
+                    "err":"invite does not exist"
+                })),
+            )
+        }
+    };
+    let group = match authz::group_member(&db, &id, invite.group_id).await {
+        Ok(g) => g,
+        Err(e) => {
+            return (
+                e.code.status(),
+                Json(json!({
+                    "err":e.message
+                })),
+            )
+        }
+    };
+    let user = ObjectId::parse_str(&id).unwrap();
+    if invite.created_by != user && !group.can(user, GroupPermission::ManageInvites) {
+        return (
+            StatusCode::FORBIDDEN,
+            Json(json!({
+                "err":"only admins can revoke other members' invites"
+            })),
+        );
+    }
+    match db.revoke_invite(revoke.invite_id).await {
+        Ok(()) => (
+            StatusCode::OK,
+            Json(json!({
+                "success":true
+            })),
+        ),
+        Err(e) => {
+            error!("{}", e);
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({
+                    "err":e.error()
+                })),
+            )
+        }
+    }
+}
+
+pub async fn join_group(
+    Extension(db): Extension<Arc<Db>>,
+    Extension(manager): Extension<Arc<Mutex<Manager>>>,
+    req: Request<Body>,
+) -> impl IntoResponse {
+    let (parts, body) = req.into_parts();
+    let id = match authenticate(parts, &db).await {
+        Ok(id) => id,
+        Err(reply) => return reply,
+    };
+    let bytes = match read_body(body).await {
+        Ok(bytes) => bytes,
+        Err(reply) => return reply,
+    };
+    let join = match parse_body::<InviteJoin>(&bytes) {
+        Ok(j) => j,
+        Err(e) => return e,
+    };
+    let user = ObjectId::parse_str(&id).unwrap();
+    let invite = db.find_invite(&join.token).await;
+    let group = match &invite {
+        Some(invite) => db.find_group(invite.group_id).await,
+        None => None,
+    };
+    let (invite, group) = match (invite, group) {
+        (Some(i), Some(g)) => (i, g),
+        _ => {
+            return (
+                StatusCode::NOT_FOUND,
+                Json(json!({
+                    "err":"invite does not exist"
+                })),
+            )
+        }
+    };
+    if group.banned.contains(&user) {
+        return (
+            StatusCode::FORBIDDEN,
+            Json(json!({
+                "err":"you are banned from this group"
+            })),
+        );
+    }
+    // already in, nothing to use the invite up for
+    if group.members.contains(&user) {
+        return (
+            StatusCode::OK,
+            Json(json!({
+                "success":true,
+                "group":group
+            })),
+        );
+    }
+    // the invite gets them as far as the approval queue, and is only used up if they are let in
+    if group.settings.join_approval {
+        if !invite.usable(DateTime::now()) {
+            return (
+                StatusCode::GONE,
+                Json(json!({
+                    "err":"invite has expired, been used up or been revoked"
+                })),
+            );
+        }
+        return submit_join_request(&db, &manager, &group, user, invite.id).await;
+    }
+    match db.use_invite(&join.token).await {
+        Ok(Some(_)) => (),
+        Ok(None) => {
+            return (
+                StatusCode::GONE,
+                Json(json!({
+                    "err":"invite has expired, been used up or been revoked"
+                })),
+            )
+        }
+        Err(e) => {
+            error!("{}", e);
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({
+                    "err":e.error()
+                })),
+            );
+        }
+    }
+    let res = db.add_group_member(group.id.unwrap(), user).await;
+    match group_changed(res, "group no longer exists") {
+        Ok(group) => {
+            let event = SystemEvent::MemberJoined { user };
+            let msg = SystemMessage::group(group.id.unwrap(), user, event);
+            post_system_message(&db, &manager, msg, &group.members).await;
+            (
+                StatusCode::OK,
+                Json(json!({
+                    "success":true,
+                    "group":group
+                })),
+            )
+        }
+        Err(e) => e,
+    }
+}
+
+// Hands back the invite use an approval took when the approval did not go through
+async fn release_invite_use(db: &Db, invite: Option<ObjectId>) {
+    if let Some(invite) = invite {
+        if let Err(e) = db.release_invite_use(invite).await {
+            error!("{}", e);
+        }
+    }
+}
+
+async fn submit_join_request(
+    db: &Db,
+    manager: &Mutex<Manager>,
+    group: &Group,
+    user: ObjectId,
+    invite_id: Option<ObjectId>,
+) -> Reply {
+    let request = GroupJoinRequest::new(group.id.unwrap(), user, invite_id);
+    match db.create_join_request(request).await {
+        Ok(request) => {
+            let frame = ServerFrame::event(ServerEvent::JoinRequested {
+                request: request.clone(),
+            });
+            let admins = group.members_who_can(GroupPermission::HandleJoinRequests);
+            manager.lock().await.send_to_users(&admins, &frame, None);
+            (
+                StatusCode::ACCEPTED,
+                Json(json!({
+                    "success":true,
+                    "pending":true,
+                    "request":request
+                })),
+            )
+        }
+        Err(e) => {
+            error!("{}", e);
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({
+                    "err":e.error()
+                })),
+            )
+        }
+    }
+}
+
+pub async fn request_join(
+    Extension(db): Extension<Arc<Db>>,
+    Extension(manager): Extension<Arc<Mutex<Manager>>>,
+    req: Request<Body>,
+) -> impl IntoResponse {
+    let (parts, body) = req.into_parts();
+    let id = match authenticate(parts, &db).await {
+        Ok(id) => id,
+        Err(reply) => return reply,
+    };
+    let bytes = match read_body(body).await {
+        Ok(bytes) => bytes,
+        Err(reply) => return reply,
+    };
+    let join = match parse_body::<NewJoinRequest>(&bytes) {
+        Ok(j) => j,
+        Err(e) => return e,
+    };
+    let user = ObjectId::parse_str(&id).unwrap();
+    let group = match db.find_group(join.group_id).await {
+        Some(g) => g,
+        None => {
+            return (
+                StatusCode::NOT_FOUND,
+                Json(json!({
+                    "err":"group does not exist"
+                })),
+            )
         }
-        Ok(HandleMember::Remove(r)) => {
-            let res = db
-                .add_or_remove_members(id, r.group_id, r.user_ids, "remove")
-                .await;
-            match res {
-                Ok(_) => (
-                    StatusCode::OK,
+    };
+    if group.members.contains(&user) {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(json!({
+                "err":"you are already a member of this group"
+            })),
+        );
+    }
+    if group.banned.contains(&user) {
+        return (
+            StatusCode::FORBIDDEN,
+            Json(json!({
+                "err":"you are banned from this group"
+            })),
+        );
+    }
+    if !group.settings.join_approval {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(json!({
+                "err":"this group does not take join requests, ask a member for an invite"
+            })),
+        );
+    }
+    submit_join_request(&db, &manager, &group, user, None).await
+}
+
+pub async fn handle_join_request(
+    Extension(db): Extension<Arc<Db>>,
+    Extension(manager): Extension<Arc<Mutex<Manager>>>,
+    req: Request<Body>,
+) -> impl IntoResponse {
+    let (parts, body) = req.into_parts();
+    let id = match authenticate(parts, &db).await {
+        Ok(id) => id,
+        Err(reply) => return reply,
+    };
+    let bytes = match read_body(body).await {
+        Ok(bytes) => bytes,
+        Err(reply) => return reply,
+    };
+    let (request_id, status) = match parse_body::<JoinRequestAction>(&bytes) {
+        Ok(JoinRequestAction::Approve { request_id }) => (request_id, "approved"),
+        Ok(JoinRequestAction::Reject { request_id }) => (request_id, "rejected"),
+        Err(e) => return e,
+    };
+    let pending = match db.find_join_request(request_id).await {
+        Some(r) => r,
+        None => {
+            return (
+                StatusCode::NOT_FOUND,
+                Json(json!({
+                    "err":"join request does not exist"
+                })),
+            )
+        }
+    };
+    let group_id = pending.group_id;
+    let group = match authz::group_permission(
+        &db,
+        &id,
+        group_id,
+        GroupPermission::HandleJoinRequests,
+        "only admins can handle join requests",
+    )
+    .await
+    {
+        Ok(g) => g,
+        Err(e) => {
+            return (
+                e.code.status(),
+                Json(json!({
+                    "err":e.message
+                })),
+            )
+        }
+    };
+    // letting them in takes a use of the invite they came with, same as joining with it directly
+    let invite = match (status, pending.invite_id) {
+        ("approved", Some(invite_id)) => match db.use_invite_with_id(invite_id).await {
+            Ok(Some(_)) => Some(invite_id),
+            Ok(None) => {
+                return (
+                    StatusCode::GONE,
                     Json(json!({
-                        "success":true
+                        "err":"the invite this request came with has expired, been used up or been revoked"
                     })),
-                ),
-                Err(e) => (
-                    StatusCode::BAD_REQUEST,
+                )
+            }
+            Err(e) => {
+                error!("{}", e);
+                return (
+                    StatusCode::INTERNAL_SERVER_ERROR,
                     Json(json!({
                         "err":e.error()
                     })),
-                ),
+                );
             }
+        },
+        _ => None,
+    };
+    let request = match db
+        .resolve_join_request(request_id, status, ObjectId::parse_str(&id).unwrap())
+        .await
+    {
+        Ok(Some(r)) => r,
+        Ok(None) => {
+            release_invite_use(&db, invite).await;
+            return (
+                StatusCode::CONFLICT,
+                Json(json!({
+                    "err":"join request was already handled"
+                })),
+            );
         }
         Err(e) => {
-            error!("{}", e.to_string());
+            error!("{}", e);
+            release_invite_use(&db, invite).await;
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({
+                    "err":e.error()
+                })),
+            );
+        }
+    };
+    let group = if status == "approved" {
+        let res = db.add_group_member(group_id, request.from_id).await;
+        match group_changed(res, "user has been banned from this group") {
+            Ok(g) => g,
+            Err(e) => {
+                release_invite_use(&db, invite).await;
+                return e;
+            }
+        }
+    } else {
+        group
+    };
+    // the requester and the other admins, whose pending lists just changed
+    let mut notify = group.members_who_can(GroupPermission::HandleJoinRequests);
+    notify.push(request.from_id);
+    let handled = ServerFrame::event(ServerEvent::JoinRequestHandled {
+        request: request.clone(),
+    });
+    manager.lock().await.send_to_users(&notify, &handled, None);
+    if status == "approved" {
+        let event = SystemEvent::MemberJoined {
+            user: request.from_id,
+        };
+        let actor = ObjectId::parse_str(&id).unwrap();
+        let msg = SystemMessage::group(group_id, actor, event);
+        post_system_message(&db, &manager, msg, &group.members).await;
+    }
+    (
+        StatusCode::OK,
+        Json(json!({
+            "success":true,
+            "request":request
+        })),
+    )
+}
+
+// `actor` needs `permission` and a higher role than `target`
+async fn moderate(
+    db: &Db,
+    actor: &str,
+    group_id: ObjectId,
+    target: ObjectId,
+    permission: GroupPermission,
+    denied: &str,
+) -> Result<Group, Reply> {
+    let group = authz::group_permission(db, actor, group_id, permission, denied)
+        .await
+        .map_err(|e| {
             (
-                StatusCode::BAD_REQUEST,
+                e.code.status(),
+                Json(json!({
+                    "err":e.message
+                })),
+            )
+        })?;
+    if !group.outranks(ObjectId::parse_str(actor).unwrap(), target) {
+        return Err((
+            StatusCode::FORBIDDEN,
+            Json(json!({
+                "err":"you can only act on members below your role"
+            })),
+        ));
+    }
+    Ok(group)
+}
+
+// Anyone but the owner can leave, the owner has to hand the group over first
+pub async fn leave_group(
+    Extension(db): Extension<Arc<Db>>,
+    Extension(manager): Extension<Arc<Mutex<Manager>>>,
+    req: Request<Body>,
+) -> impl IntoResponse {
+    let (parts, body) = req.into_parts();
+    let id = match authenticate(parts, &db).await {
+        Ok(id) => id,
+        Err(reply) => return reply,
+    };
+    let bytes = match read_body(body).await {
+        Ok(bytes) => bytes,
+        Err(reply) => return reply,
+    };
+    let leave = match parse_body::<LeaveGroup>(&bytes) {
+        Ok(l) => l,
+        Err(e) => return e,
+    };
+    let user = ObjectId::parse_str(&id).unwrap();
+    let group = match authz::group_member(&db, &id, leave.group_id).await {
+        Ok(g) => g,
+        Err(e) => {
+            return (
+                e.code.status(),
+                Json(json!({
+                    "err":e.message
+                })),
+            )
+        }
+    };
+    if group.role_of(user) == Some(GroupRole::Owner) && group.members.len() > 1 {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(json!({
+                "err":"transfer ownership before leaving the group"
+            })),
+        );
+    }
+    let res = db
+        .remove_group_members(leave.group_id, &[user], false)
+        .await;
+    match group_changed(res, "group no longer exists") {
+        Ok(group) => {
+            let recipients: Vec<ObjectId> = group.members.iter().copied().chain([user]).collect();
+            let event = SystemEvent::MemberLeft { user };
+            let msg = SystemMessage::group(leave.group_id, user, event);
+            post_system_message(&db, &manager, msg, &recipients).await;
+            (
+                StatusCode::OK,
+                Json(json!({
+                    "success":true
+                })),
+            )
+        }
+        Err(e) => e,
+    }
+}
+
+pub async fn kick_member(
+    Extension(db): Extension<Arc<Db>>,
+    Extension(manager): Extension<Arc<Mutex<Manager>>>,
+    req: Request<Body>,
+) -> impl IntoResponse {
+    let (parts, body) = req.into_parts();
+    let id = match authenticate(parts, &db).await {
+        Ok(id) => id,
+        Err(reply) => return reply,
+    };
+    let bytes = match read_body(body).await {
+        Ok(bytes) => bytes,
+        Err(reply) => return reply,
+    };
+    let kick = match parse_body::<MemberAction>(&bytes) {
+        Ok(k) => k,
+        Err(e) => return e,
+    };
+    let group = match moderate(
+        &db,
+        &id,
+        kick.group_id,
+        kick.user_id,
+        GroupPermission::RemoveMembers,
+        "only admins can remove members",
+    )
+    .await
+    {
+        Ok(g) => g,
+        Err(e) => return e,
+    };
+    if !group.members.contains(&kick.user_id) {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(json!({
+                "err":"user is not a member of this group"
+            })),
+        );
+    }
+    let res = db
+        .remove_group_members(kick.group_id, &[kick.user_id], false)
+        .await;
+    match group_changed(res, "group no longer exists") {
+        Ok(group) => {
+            let recipients: Vec<ObjectId> = group
+                .members
+                .iter()
+                .copied()
+                .chain([kick.user_id])
+                .collect();
+            let event = SystemEvent::MemberKicked { user: kick.user_id };
+            let actor = ObjectId::parse_str(&id).unwrap();
+            let msg = SystemMessage::group(kick.group_id, actor, event);
+            post_system_message(&db, &manager, msg, &recipients).await;
+            (
+                StatusCode::OK,
+                Json(json!({
+                    "success":true,
+                    "group":group
+                })),
+            )
+        }
+        Err(e) => e,
+    }
+}
+
+// Removes the user if they are a member and keeps them out until unbanned
+pub async fn ban_member(
+    Extension(db): Extension<Arc<Db>>,
+    Extension(manager): Extension<Arc<Mutex<Manager>>>,
+    req: Request<Body>,
+) -> impl IntoResponse {
+    let (parts, body) = req.into_parts();
+    let id = match authenticate(parts, &db).await {
+        Ok(id) => id,
+        Err(reply) => return reply,
+    };
+    let bytes = match read_body(body).await {
+        Ok(bytes) => bytes,
+        Err(reply) => return reply,
+    };
+    let ban = match parse_body::<MemberAction>(&bytes) {
+        Ok(b) => b,
+        Err(e) => return e,
+    };
+    if let Err(e) = moderate(
+        &db,
+        &id,
+        ban.group_id,
+        ban.user_id,
+        GroupPermission::BanMembers,
+        "only admins can ban members",
+    )
+    .await
+    {
+        return e;
+    }
+    let res = db
+        .remove_group_members(ban.group_id, &[ban.user_id], true)
+        .await;
+    match group_changed(res, "group no longer exists") {
+        Ok(group) => {
+            let recipients: Vec<ObjectId> =
+                group.members.iter().copied().chain([ban.user_id]).collect();
+            let event = SystemEvent::MemberBanned { user: ban.user_id };
+            let actor = ObjectId::parse_str(&id).unwrap();
+            let msg = SystemMessage::group(ban.group_id, actor, event);
+            post_system_message(&db, &manager, msg, &recipients).await;
+            (
+                StatusCode::OK,
+                Json(json!({
+                    "success":true,
+                    "group":group
+                })),
+            )
+        }
+        Err(e) => e,
+    }
+}
+
+// Lifts the ban only, getting back in still needs an invite or an add
+pub async fn unban_member(
+    Extension(db): Extension<Arc<Db>>,
+    Extension(manager): Extension<Arc<Mutex<Manager>>>,
+    req: Request<Body>,
+) -> impl IntoResponse {
+    let (parts, body) = req.into_parts();
+    let id = match authenticate(parts, &db).await {
+        Ok(id) => id,
+        Err(reply) => return reply,
+    };
+    let bytes = match read_body(body).await {
+        Ok(bytes) => bytes,
+        Err(reply) => return reply,
+    };
+    let unban = match parse_body::<MemberAction>(&bytes) {
+        Ok(u) => u,
+        Err(e) => return e,
+    };
+    let group = match authz::group_permission(
+        &db,
+        &id,
+        unban.group_id,
+        GroupPermission::BanMembers,
+        "only admins can unban members",
+    )
+    .await
+    {
+        Ok(g) => g,
+        Err(e) => {
+            return (
+                e.code.status(),
+                Json(json!({
+                    "err":e.message
+                })),
+            )
+        }
+    };
+    if !group.banned.contains(&unban.user_id) {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(json!({
+                "err":"user is not banned from this group"
+            })),
+        );
+    }
+    let res = db.unban_group_member(unban.group_id, unban.user_id).await;
+    match group_changed(res, "group no longer exists") {
+        Ok(group) => {
+            let event = SystemEvent::MemberUnbanned {
+                user: unban.user_id,
+            };
+            let actor = ObjectId::parse_str(&id).unwrap();
+            let msg = SystemMessage::group(unban.group_id, actor, event);
+            post_system_message(&db, &manager, msg, &group.members).await;
+            (
+                StatusCode::OK,
+                Json(json!({
+                    "success":true,
+                    "group":group
+                })),
+            )
+        }
+        Err(e) => e,
+    }
+}
+
+pub async fn mute_member(
+    Extension(db): Extension<Arc<Db>>,
+    Extension(manager): Extension<Arc<Mutex<Manager>>>,
+    req: Request<Body>,
+) -> impl IntoResponse {
+    let (parts, body) = req.into_parts();
+    let id = match authenticate(parts, &db).await {
+        Ok(id) => id,
+        Err(reply) => return reply,
+    };
+    let bytes = match read_body(body).await {
+        Ok(bytes) => bytes,
+        Err(reply) => return reply,
+    };
+    let mute = match parse_body::<MuteMember>(&bytes) {
+        Ok(m) => m,
+        Err(e) => return e,
+    };
+    if mute.duration == 0 || mute.duration > MAX_MUTE_SECS {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(json!({
+                "err":format!("duration must be between 1 and {} seconds", MAX_MUTE_SECS)
+            })),
+        );
+    }
+    if let Err(e) = moderate(
+        &db,
+        &id,
+        mute.group_id,
+        mute.user_id,
+        GroupPermission::MuteMembers,
+        "only moderators can mute members",
+    )
+    .await
+    {
+        return e;
+    }
+    let until =
+        DateTime::from_millis(DateTime::now().timestamp_millis() + mute.duration as i64 * 1000);
+    let res = db
+        .set_group_mute(mute.group_id, mute.user_id, Some(until))
+        .await;
+    match group_changed(res, "user is not a member of this group") {
+        Ok(group) => {
+            let event = SystemEvent::MemberMuted {
+                user: mute.user_id,
+                until,
+            };
+            let actor = ObjectId::parse_str(&id).unwrap();
+            let msg = SystemMessage::group(mute.group_id, actor, event);
+            post_system_message(&db, &manager, msg, &group.members).await;
+            (
+                StatusCode::OK,
+                Json(json!({
+                    "success":true,
+                    "until":until.try_to_rfc3339_string().ok()
+                })),
+            )
+        }
+        Err(e) => e,
+    }
+}
+
+pub async fn unmute_member(
+    Extension(db): Extension<Arc<Db>>,
+    Extension(manager): Extension<Arc<Mutex<Manager>>>,
+    req: Request<Body>,
+) -> impl IntoResponse {
+    let (parts, body) = req.into_parts();
+    let id = match authenticate(parts, &db).await {
+        Ok(id) => id,
+        Err(reply) => return reply,
+    };
+    let bytes = match read_body(body).await {
+        Ok(bytes) => bytes,
+        Err(reply) => return reply,
+    };
+    let unmute = match parse_body::<MemberAction>(&bytes) {
+        Ok(u) => u,
+        Err(e) => return e,
+    };
+    if let Err(e) = moderate(
+        &db,
+        &id,
+        unmute.group_id,
+        unmute.user_id,
+        GroupPermission::MuteMembers,
+        "only moderators can unmute members",
+    )
+    .await
+    {
+        return e;
+    }
+    let res = db
+        .set_group_mute(unmute.group_id, unmute.user_id, None)
+        .await;
+    match group_changed(res, "user is not a member of this group") {
+        Ok(group) => {
+            let event = SystemEvent::MemberUnmuted {
+                user: unmute.user_id,
+            };
+            let actor = ObjectId::parse_str(&id).unwrap();
+            let msg = SystemMessage::group(unmute.group_id, actor, event);
+            post_system_message(&db, &manager, msg, &group.members).await;
+            (
+                StatusCode::OK,
                 Json(json!({
-                    "err":e.to_string()
+                    "success":true
                 })),
             )
         }
+        Err(e) => e,
     }
 }
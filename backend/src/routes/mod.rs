@@ -1,4 +1,4 @@
-use axum::{routing::{get, post}, Json, Router};
+use axum::{routing::{delete, get, post}, Json, Router};
 use serde_json::json;
 mod auth;
 mod user;
@@ -12,6 +12,9 @@ pub fn handle_auth_routes() -> Router {
         .route("/signup", post(auth::signup))
         .route("/login", post(auth::login))
         .route("/logout", get(auth::logout))
+        .route("/refresh", post(auth::refresh))
+        .route("/sessions", get(auth::list_sessions).delete(auth::revoke_all_sessions))
+        .route("/sessions/{id}", delete(auth::revoke_session))
         .route("/session", get(auth::session));
     // .route("/verify_user", post(user::verify))
     router
@@ -26,6 +29,8 @@ pub fn handle_api_routes() -> Router{
         }))
         .nest("/requests",api_request_routes())
         .nest("/chat", api_chat_routes())
+        .nest("/group", api_group_routes())
+        .route("/presence", post(api::get_presence))
         .route("/get_my_id", get(api::get_my_id));
     router
 }
@@ -53,7 +58,21 @@ pub fn handle_create_routes() -> Router{
 
 pub fn handle_group_routes() -> Router{
     let router = Router::new()
-        .route("/manage_members", post(group::add_or_remove_members));
+        .route("/manage_members", post(group::add_or_remove_members))
+        .route("/update", post(group::update_group))
+        .route("/role", post(group::change_role))
+        .route("/transfer_ownership", post(group::transfer_ownership))
+        .route("/invite", post(group::create_invite))
+        .route("/invite/revoke", post(group::revoke_invite))
+        .route("/join", post(group::join_group))
+        .route("/join_requests", post(group::request_join))
+        .route("/join_requests/handle", post(group::handle_join_request))
+        .route("/leave", post(group::leave_group))
+        .route("/kick", post(group::kick_member))
+        .route("/ban", post(group::ban_member))
+        .route("/unban", post(group::unban_member))
+        .route("/mute", post(group::mute_member))
+        .route("/unmute", post(group::unmute_member));
     router
 }
 
@@ -76,4 +95,17 @@ fn api_chat_routes() -> Router {
 fn api_messages_routes() -> Router{
     Router::new()
         .route("/get_messages/{chat_id}", get(api::get_messages))
-}
\ No newline at end of file
+        .route("/send", post(api::send_message))
+        .route("/edit", post(api::edit_message))
+        .route("/delete", post(api::delete_message))
+}
+
+fn api_group_routes() -> Router {
+    Router::new()
+        .route("/list", get(api::get_groups))
+        .route("/{id}/messages", get(api::get_group_messages))
+        .route("/{id}/threads/{thread_id}/messages", get(api::get_thread_replies))
+        .route("/{id}/invites", get(api::get_group_invites))
+        .route("/{id}/join_requests", get(api::get_join_requests))
+        .route("/{id}/read_markers", get(api::get_read_markers))
+}
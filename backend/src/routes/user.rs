@@ -59,7 +59,7 @@ pub async fn search<T>(Extension(db): Extension<Arc<Db>>, req: Request<T>) -> im
                     })),
                 );
             }
-            let id = extract_cookie(parts).await.unwrap().sub;
+            let id = extract_cookie(parts, &db).await.unwrap().sub;
             let res = db.find_users_with_substring(value.to_string()).await;
             match res {
                 Ok(mut cursor) => {
@@ -104,7 +104,7 @@ pub async fn send_req(Extension(db): Extension<Arc<Db>>, r: Request<Body>) -> im
     let req = from_str::<FriendReq>(&data);
     match req {
         Ok(r) => {
-            let from_id = extract_cookie(parts).await.unwrap().sub;
+            let from_id = extract_cookie(parts, &db).await.unwrap().sub;
             let request = Requests::new_from_friend_req(r, from_id);
             let res = db.add_friend_request(request).await;
             match res {
@@ -170,7 +170,7 @@ pub async fn send_req(Extension(db): Extension<Arc<Db>>, r: Request<Body>) -> im
 //     req: Request<Body>,
 // ) -> impl IntoResponse {
 //     let (parts , body) = req.into_parts();
-//     let claims = extract_cookie(parts).await.unwrap();
+//     let claims = extract_cookie(parts, &db).await.unwrap();
 //     let data = to_bytes(body, usize::MAX).await.unwrap();
 //     let mut request = from_str::<RequestHandler>(String::from_utf8_lossy(&data).into_owned().as_str()).unwrap();
 //     request.from_id = Some(claims.sub.into_object_id());
@@ -1,17 +1,18 @@
-use std::{collections::HashMap, sync::Arc};
+use std::{collections::HashMap, net::SocketAddr, sync::Arc, time::Duration};
 
 use axum::{Extension, Router, http::{HeaderValue, Method, header}, middleware};
 use mongodb::bson::oid::ObjectId;
-use tokio::{net::TcpListener, sync::Mutex};
+use tokio::{net::TcpListener, sync::Mutex, time};
 use tower_http::cors::{AllowOrigin, CorsLayer};
 
 use crate::{
     db::Db,
     middleware::auth_middleware,
     routes::{
-        chat::{Client, Manager},
+        chat::{refresh_presence, Client, Manager},
         *,
     },
+    utils::check_socket_timeouts,
 };
 
 pub struct Server {
@@ -33,8 +34,10 @@ impl Server {
         }
     }
     pub async fn listen(self) {
+        check_socket_timeouts();
         let listener = TcpListener::bind(self.addr.clone()).await.unwrap();
         let db = self.db.clone();
+        let manager = self.manager.clone();
         let allowed_origins = AllowOrigin::list(["http://localhost:5173".parse::<HeaderValue>().unwrap(),"https://glooo-rust.vercel.app".parse::<HeaderValue>().unwrap()]);
 
         let cors = CorsLayer::new()
@@ -46,10 +49,24 @@ impl Server {
             .allow_methods([Method::GET, Method::POST,Method::DELETE,Method::OPTIONS])
             // 4. Allow specific headers that might be sent in a request
             .allow_headers([header::AUTHORIZATION, header::ACCEPT, header::CONTENT_TYPE,header::ORIGIN]);
+        // Nothing else notices a user going quiet, so look at everyone online now and then
+        let presence_db = db.clone();
+        let presence_manager = manager.clone();
+        tokio::spawn(async move {
+            let mut interval = time::interval(Duration::from_secs(30));
+            loop {
+                interval.tick().await;
+                let users = presence_manager.lock().await.present_users();
+                for user in users {
+                    refresh_presence(&presence_db, &presence_manager, &user).await;
+                }
+            }
+        });
         let app = self
             .manage_routers()
             .await
             .layer(Extension(db.clone()))
+            .layer(Extension(manager))
             .layer(cors);
         // tokio::spawn(async move {
         //     let mut interval = time::interval(Duration::from_secs(10 * 60));
@@ -67,7 +84,12 @@ impl Server {
         //         }
         //     }
         // });
-        axum::serve(listener, app).await.unwrap();
+        axum::serve(
+            listener,
+            app.into_make_service_with_connect_info::<SocketAddr>(),
+        )
+        .await
+        .unwrap();
     }
 
     async fn manage_routers(self) -> Router {
@@ -77,7 +99,6 @@ impl Server {
         router = router.nest("/group", handle_group_routes());
         router = router
             .nest("/chat", handle_chat_routes())
-            .layer(Extension(self.manager.clone()))
             .layer(Extension(self.group_man.clone()));
         router = router
             .nest("/user", handle_user_routes())
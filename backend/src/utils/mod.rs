@@ -1,33 +1,184 @@
-use std::env;
+use std::{
+    env,
+    net::{IpAddr, SocketAddr},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
 
-use axum::{http::request::Parts};
-use jsonwebtoken::{Algorithm, DecodingKey, Validation};
-use log::error;
+use axum::http::{header, request::Parts, HeaderMap};
+use cookie::Cookie;
+use jsonwebtoken::{encode, Algorithm, DecodingKey, EncodingKey, Header, Validation};
+use log::{error, warn};
+use rand::{distributions::Alphanumeric, Rng};
 
 use crate::{
     db::Db,
-    models::{Claims, User},
+    models::{Claims, DeviceInfo, MyError, RefreshClaims, User},
 };
 
-pub async fn extract_cookie_for_ws(parts: Parts) -> Option<String> {
-    let cookie = parts.headers.get("Cookie");
-    match cookie {
-        Some(cookie) => {
-            let jwt = cookie.to_str().unwrap();
-            let token = jwt.strip_prefix("jwt=");
-            match token {
-                Some(t) => {
-                    let claims = validate_jwt(t);
-                    match claims {
-                        Ok(c) => Some(c.sub),
-                        Err(e) => {
-                            error!("{}",e);
-                            None
-                        }
-                    }
-                }
-                None => {
-                    error!("jwt is going crazy 1");
+pub const ACCESS_COOKIE: &str = "jwt";
+pub const REFRESH_COOKIE: &str = "refresh";
+pub const ACCESS_TOKEN_TTL: Duration = Duration::from_secs(15 * 60);
+pub const REFRESH_TOKEN_TTL: Duration = Duration::from_secs(28 * 24 * 3600);
+// How long a rotated refresh token is still accepted, for tabs that refreshed at the same time
+pub const REFRESH_GRACE: Duration = Duration::from_secs(30);
+const DEFAULT_EDIT_WINDOW: Duration = Duration::from_secs(15 * 60);
+const DEFAULT_DELETE_WINDOW: Duration = Duration::from_secs(60 * 60);
+const DEFAULT_PING_INTERVAL: Duration = Duration::from_secs(25);
+const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+fn window_from_env(var: &str, default: Duration) -> Duration {
+    env::var(var)
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .map(Duration::from_secs)
+        .unwrap_or(default)
+}
+
+// How long after sending a message its author may still edit it, MESSAGE_EDIT_WINDOW_SECS overrides it
+pub fn edit_window() -> Duration {
+    window_from_env("MESSAGE_EDIT_WINDOW_SECS", DEFAULT_EDIT_WINDOW)
+}
+
+// How long a message can be deleted for everyone, MESSAGE_DELETE_WINDOW_SECS overrides it
+pub fn delete_window() -> Duration {
+    window_from_env("MESSAGE_DELETE_WINDOW_SECS", DEFAULT_DELETE_WINDOW)
+}
+
+// How often the server pings every socket, WS_PING_INTERVAL_SECS overrides it
+pub fn ping_interval() -> Duration {
+    window_from_env("WS_PING_INTERVAL_SECS", DEFAULT_PING_INTERVAL)
+}
+
+// A socket that sent nothing, not even a pong, for this long is closed.
+// WS_IDLE_TIMEOUT_SECS overrides it. One no longer than the ping interval would close sockets
+// that are only waiting for the next ping, so it is raised to two ping intervals.
+pub fn idle_timeout() -> Duration {
+    let idle = window_from_env("WS_IDLE_TIMEOUT_SECS", DEFAULT_IDLE_TIMEOUT);
+    keep_above(idle, ping_interval())
+}
+
+fn keep_above(idle: Duration, period: Duration) -> Duration {
+    if idle <= period {
+        period * 2
+    } else {
+        idle
+    }
+}
+
+/// Warns at startup when WS_IDLE_TIMEOUT_SECS had to be raised, see idle_timeout
+pub fn check_socket_timeouts() {
+    let configured = window_from_env("WS_IDLE_TIMEOUT_SECS", DEFAULT_IDLE_TIMEOUT);
+    let period = ping_interval();
+    if configured <= period {
+        warn!(
+            "WS_IDLE_TIMEOUT_SECS ({:?}) is not longer than WS_PING_INTERVAL_SECS ({:?}), using {:?}",
+            configured,
+            period,
+            keep_above(configured, period)
+        );
+    }
+}
+
+// Refresh tokens are signed with their own key so one can never be used as an access token
+fn secret(refresh: bool) -> String {
+    let secret = env::var("JWT_SECRET").unwrap();
+    if refresh {
+        format!("{}:refresh", secret)
+    } else {
+        secret
+    }
+}
+
+fn expiry(ttl: Duration) -> usize {
+    let exp = SystemTime::now().duration_since(UNIX_EPOCH).unwrap() + ttl;
+    exp.as_secs() as usize
+}
+
+pub fn find_cookie(headers: &HeaderMap, name: &str) -> Option<String> {
+    headers
+        .get_all(header::COOKIE)
+        .iter()
+        .filter_map(|h| h.to_str().ok())
+        .flat_map(Cookie::split_parse)
+        .filter_map(Result::ok)
+        .find(|c| c.name() == name)
+        .map(|c| c.value().to_string())
+}
+
+// Peers allowed to say who the client is, TRUSTED_PROXIES is a comma separated list of IPs
+fn trusted_proxy(ip: IpAddr) -> bool {
+    env::var("TRUSTED_PROXIES")
+        .map(|list| {
+            list.split(',')
+                .filter_map(|p| p.trim().parse::<IpAddr>().ok())
+                .any(|p| p == ip)
+        })
+        .unwrap_or(false)
+}
+
+// Behind the hosting proxy the peer address is the proxy, so X-Forwarded-For is used,
+// but only when it comes from a trusted proxy since anyone else can send whatever they like
+pub fn device_info(headers: &HeaderMap, addr: SocketAddr) -> DeviceInfo {
+    let user_agent = headers
+        .get(header::USER_AGENT)
+        .and_then(|h| h.to_str().ok())
+        .map(String::from);
+    let forwarded = if trusted_proxy(addr.ip()) {
+        headers
+            .get("X-Forwarded-For")
+            .and_then(|h| h.to_str().ok())
+            // the proxy appends the address it saw, anything before that came from the client
+            .and_then(|h| h.rsplit(',').next())
+            .map(|ip| ip.trim().to_string())
+    } else {
+        None
+    };
+    let ip = forwarded.unwrap_or_else(|| addr.ip().to_string());
+    DeviceInfo {
+        user_agent,
+        ip: Some(ip),
+    }
+}
+
+pub fn new_token_id() -> String {
+    rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(32)
+        .map(char::from)
+        .collect()
+}
+
+pub fn create_access_token(user_id: &str, session_id: &str) -> Result<String, MyError> {
+    let claims = Claims {
+        sub: user_id.to_string(),
+        sid: session_id.to_string(),
+        exp: expiry(ACCESS_TOKEN_TTL),
+    };
+    let key = EncodingKey::from_secret(secret(false).as_bytes());
+    encode(&Header::new(Algorithm::HS256), &claims, &key)
+        .map_err(|e| MyError::from_error(e, "utils : create access token"))
+}
+
+pub fn create_refresh_token(user_id: &str, session_id: &str, jti: &str) -> Result<String, MyError> {
+    let claims = RefreshClaims {
+        sub: user_id.to_string(),
+        sid: session_id.to_string(),
+        jti: jti.to_string(),
+        exp: expiry(REFRESH_TOKEN_TTL),
+    };
+    let key = EncodingKey::from_secret(secret(true).as_bytes());
+    encode(&Header::new(Algorithm::HS256), &claims, &key)
+        .map_err(|e| MyError::from_error(e, "utils : create refresh token"))
+}
+
+pub async fn extract_cookie_for_ws(parts: Parts, db: &Db) -> Option<Claims> {
+    match find_cookie(&parts.headers, ACCESS_COOKIE) {
+        Some(t) => {
+            let claims = validate_jwt(&t, db).await;
+            match claims {
+                Ok(c) => Some(c),
+                Err(e) => {
+                    error!("{}", e);
                     None
                 }
             }
@@ -39,47 +190,62 @@ pub async fn extract_cookie_for_ws(parts: Parts) -> Option<String> {
     }
 }
 
-pub async fn extract_cookie(parts: Parts) -> Result<Claims, String> {
-    let cookie = parts.headers.get("Cookie");
-    match cookie {
-        Some(cookie) => {
-            let jwt = cookie.to_str().unwrap();
-            let res = jwt.split_once("=");
-            match res {
-                Some((name, token)) => {
-                    if name == "jwt" {
-                        validate_jwt(token)
-                    } else {
-                        Err(String::from("invalid name of token"))
-                    }
-                }
-                None => Err(String::from("jwt is going crazy")),
-            }
-        }
+pub async fn extract_cookie(parts: Parts, db: &Db) -> Result<Claims, String> {
+    match find_cookie(&parts.headers, ACCESS_COOKIE) {
+        Some(token) => validate_jwt(&token, db).await,
         None => Err("cookie invalid".to_string()),
     }
 }
 
 pub async fn extract_cookie_into_user(parts: &Parts, db: &Db) -> Result<Option<User>, String> {
-    let claims = extract_cookie(parts.clone()).await;
+    let claims = extract_cookie(parts.clone(), db).await;
     match claims {
         Ok(claims) => Ok(db.find_user_with_id(claims.sub).await),
         Err(e) => Err(e),
     }
 }
 
-pub fn validate_jwt(token: &str) -> Result<Claims, String> {
-    let secret = env::var("JWT_SECRET").unwrap();
-    // println!("{}",secret);
-    let key = DecodingKey::from_secret(secret.as_bytes());
+pub async fn validate_jwt(token: &str, db: &Db) -> Result<Claims, String> {
+    let key = DecodingKey::from_secret(secret(false).as_bytes());
     let validation = Validation::new(Algorithm::HS256);
-    let token_data = jsonwebtoken::decode(token, &key, &validation);
-    // println!("{:?}", token_data);
+    let token_data = jsonwebtoken::decode::<Claims>(token, &key, &validation);
     match token_data {
-        Ok(claims) => Ok(claims.claims),
+        Ok(data) => {
+            if db.session_is_active(data.claims.sid.clone()).await {
+                Ok(data.claims)
+            } else {
+                Err(String::from("session revoked or expired"))
+            }
+        }
         Err(e) => Err(e.to_string()),
     }
 }
 
+pub fn validate_refresh_jwt(token: &str) -> Result<RefreshClaims, String> {
+    let key = DecodingKey::from_secret(secret(true).as_bytes());
+    let validation = Validation::new(Algorithm::HS256);
+    let token_data = jsonwebtoken::decode::<RefreshClaims>(token, &key, &validation);
+    match token_data {
+        Ok(data) => Ok(data.claims),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
 
+    #[test]
+    fn idle_timeout_longer_than_the_ping_is_kept() {
+        let period = Duration::from_secs(30);
+        assert_eq!(keep_above(Duration::from_secs(31), period), Duration::from_secs(31));
+    }
 
+    #[test]
+    fn idle_timeout_not_longer_than_the_ping_is_raised() {
+        let period = Duration::from_secs(30);
+        assert_eq!(keep_above(period, period), Duration::from_secs(60));
+        assert_eq!(keep_above(Duration::from_secs(5), period), Duration::from_secs(60));
+        assert_eq!(keep_above(Duration::ZERO, period), Duration::from_secs(60));
+    }
+}
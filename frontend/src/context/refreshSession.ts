@@ -0,0 +1,38 @@
+import axios, { type AxiosError, type InternalAxiosRequestConfig } from "axios";
+
+const BaseUrl: string = import.meta.env.VITE_BACKEND_URL;
+
+type RetriableConfig = InternalAxiosRequestConfig & { _retried?: boolean };
+
+let refreshing: Promise<boolean> | null = null;
+
+// Access tokens are short lived, so when one is rejected we rotate the
+// refresh token once and replay the original request.
+const refreshSession = () => {
+  if (!refreshing) {
+    refreshing = axios
+      .post(BaseUrl + "/auth/refresh", {}, { withCredentials: true })
+      .then((res) => Boolean(res.data.success))
+      .catch(() => false)
+      .finally(() => {
+        refreshing = null;
+      });
+  }
+  return refreshing;
+};
+
+export const installRefreshInterceptor = () => {
+  axios.interceptors.response.use(undefined, async (error: AxiosError) => {
+    const config = error.config as RetriableConfig | undefined;
+    const status = error.response?.status;
+    const isAuthCall = config?.url?.includes("/auth/refresh") || config?.url?.includes("/auth/login");
+    if (!config || config._retried || isAuthCall || (status !== 401 && status !== 406)) {
+      return Promise.reject(error);
+    }
+    config._retried = true;
+    if (await refreshSession()) {
+      return axios(config);
+    }
+    return Promise.reject(error);
+  });
+};
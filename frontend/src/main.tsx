@@ -3,6 +3,9 @@ import { createRoot } from 'react-dom/client'
 import './index.css';
 import App from './App.tsx'
 import { AuthProvider } from './context/AuthContextProvider.tsx';
+import { installRefreshInterceptor } from './context/refreshSession.ts';
+
+installRefreshInterceptor();
 
 createRoot(document.getElementById('root')!).render(
   <StrictMode>
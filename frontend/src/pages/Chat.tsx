@@ -14,6 +14,18 @@ interface Message {
   to_id: Id;
   content: string;
   chat_id: Id;
+  // set on entries the server writes itself, like "chat started"
+  type?: string;
+  event?: { kind: string };
+}
+
+function describeSystemEvent(event?: { kind: string }): string {
+  switch (event?.kind) {
+    case "chat_created":
+      return "Chat started";
+    default:
+      return (event?.kind ?? "update").replace(/_/g, " ");
+  }
 }
 
 interface MessageToSend {
@@ -21,12 +33,22 @@ interface MessageToSend {
   to_id: string;
   content: string;
   chat_id: string;
+  client_msg_id: string;
+}
+// Every socket frame is wrapped in a versioned envelope
+interface Frame<T> {
+  v: number;
+  id?: string;
+  op: string;
+  payload: T;
 }
+
 interface Conversation {
   id: Id;
   sender: Id,
   receiver: { id: Id; name: string; username: string };
-  last_updated_message: Message;
+  // null until the first message and after the only one is deleted
+  last_updated_message: Message | null;
 }
 
 const BaseUrl: string = import.meta.env.VITE_BACKEND_URL;
@@ -101,7 +123,14 @@ function Chat() {
 
     ws.onmessage = (msg) => {
       try {
-        const message: Message = JSON.parse(msg.data);
+        const frame: Frame<Message & { type: string }> = JSON.parse(msg.data);
+        if (frame.op === "error") {
+          console.error("Server rejected frame:", frame.id, frame.payload);
+          return;
+        }
+        if (frame.op !== "message") return;
+        const message = frame.payload;
+        if (message.type !== "direct" && !(message.type === "system" && message.chat_id)) return;
         const chatId = message.chat_id.$oid;
         setMessages((prev) => {
           // Append the message for this chat
@@ -139,14 +168,22 @@ function Chat() {
   const sendMessage = useCallback(
     (chat: Conversation, msg: string) => {
       const chat_id = chat.id.$oid;
+      const client_msg_id = crypto.randomUUID();
       const message: MessageToSend = {
         type: "direct",
         to_id: chat.receiver.id.$oid,
         content: msg,
-        chat_id
+        chat_id,
+        client_msg_id
       };
       if (socket && socket.readyState === WebSocket.OPEN) {
-        socket.send(JSON.stringify(message));
+        const frame: Frame<MessageToSend> = {
+          v: 1,
+          id: client_msg_id,
+          op: "send",
+          payload: message,
+        };
+        socket.send(JSON.stringify(frame));
         const realMsg: Message = {
           from_id: id,
           to_id: chat.receiver.id,
@@ -177,9 +214,11 @@ function Chat() {
         `${BaseUrl}/api/chat/message/get_messages/${chatId}`,
         { withCredentials: true }
       );
+      // history pages come newest-first
+      const page: Message[] = res.data.messages || [];
       setMessages((prev) => ({
         ...prev,
-        [chatId]: res.data.messages || [],
+        [chatId]: [...page].reverse(),
       }));
     } catch (err) {
       console.error("Failed to fetch messages:", err);
@@ -264,8 +303,9 @@ function Chat() {
                     {chat.receiver.name}
                   </p>
                   <p className="text-sm text-gray-500 dark:text-gray-400 truncate">
-                    {/* {chat.last_updated_message?.content || "No messages yet"} */}
-                    {chat.last_updated_message.from_id?.$oid === chat.sender.$oid ? (
+                    {!chat.last_updated_message ? (
+                      <>No messages yet</>
+                    ) : chat.last_updated_message.from_id?.$oid === chat.sender.$oid ? (
                       <>Sent: {chat.last_updated_message.content}</>
                     ) : (
                       <>Received: {chat.last_updated_message.content}</>
@@ -315,7 +355,15 @@ function Chat() {
               <>
                 {/* Messages */}
                 <div className="flex-1 overflow-y-auto p-4 space-y-3">
-                  {chatMessages.map((msg, idx) => (
+                  {chatMessages.map((msg, idx) => msg.type === "system" ? (
+                    <div
+                      key={idx}
+                      className="text-center text-xs text-gray-500 dark:text-gray-400"
+                    >
+                      {describeSystemEvent(msg.event)}
+                      <div ref={messagesEndRef} />
+                    </div>
+                  ) : (
                     <div
                       key={idx}
                       className={`flex ${msg.from_id?.$oid === selectedChat.receiver.id.$oid